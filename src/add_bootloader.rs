@@ -1,28 +1,50 @@
 use crate::log;
 use crate::oops;
 use crate::Config;
-use crate::list_dir;
-use crate::run;
+use crate::run_env;
 use crate::try_create_dir;
+use crate::compression;
+use crate::limine;
+use crate::iso9660;
+use crate::disk_image;
+use crate::disk_image::PartitionTable;
+use crate::compression::Encoder;
+use crate::clean::human_size;
+use crate::template::render;
+use crate::template::Scope;
+use crate::opt;
+use crate::opt_default;
 
 use std::fs::write;
 use std::fs::copy;
 use std::fs::OpenOptions;
 use std::fs::remove_file;
+use std::fs::remove_dir_all;
+use std::fs::create_dir_all;
 use std::fs::read_to_string;
+use std::fs::read_dir;
 use std::fs::metadata;
+use std::fs::canonicalize;
+use std::env::var;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use walkdir::WalkDir;
 
-use lz4_flex::block::compress_prepend_size;
 use cpio::newc::Builder;
 use cpio::write_cpio;
 
 
-const BUILTIN_LIMINE_CFG: &'static str = include_str!("limine.cfg");
+const BUILTIN_LIMINE_CFG: &str = include_str!("limine.cfg");
+const BUILTIN_GRUB_CFG: &str = include_str!("grub.cfg");
+/// Marks directories populated by the "uefi-dir" bootloader, which can be deleted
+const ESP_DIR_MARKER: &str = ".theseus-builder-esp";
 
 pub fn process(config: &Config) {
     let stage = "add-bootloader";
 
-    let iso = config.str("output-iso");
+    let output = output_path(stage, config);
     let nanocore_path = config.str("nanocore-path");
     let modules_dir = config.str("directories.modules");
     let isofiles_dir = config.str("directories.isofiles");
@@ -30,149 +52,448 @@ pub fn process(config: &Config) {
     let bootloader = config.str("add-bootloader.bootloader");
     let grub_mkrescue = config.str("add-bootloader.grub-mkrescue");
     let limine_config = config.str("add-bootloader.limine-config");
-    let limine_tarball = config.str("add-bootloader.limine-tarball");
-    let tarball_path = config.str("add-bootloader.tarball-path");
-    let prebuilt_dir = config.str("add-bootloader.extract-dir");
-    let prebuilt_subdir = config.str("add-bootloader.expected-subdir");
-    let downloader = config.str("add-bootloader.downloader");
+    let grub_config = config.str("add-bootloader.grub-config");
     let xorriso = config.str("add-bootloader.xorriso");
+    let iso_writer = config.str("add-bootloader.iso-writer");
+    let image_format = config.str("add-bootloader.image-format");
     let nanocore_dst = config.str("add-bootloader.nanocore-destination");
+    let codec = config.str("add-bootloader.module-compression");
+    let epoch = source_date_epoch(stage, config);
+    let epoch_string = epoch.to_string();
+    let epoch_env = [ ("SOURCE_DATE_EPOCH", epoch_string.as_str()) ];
 
     log!(stage, "adding the {} bootloader", bootloader);
 
     copy(nanocore_path, nanocore_dst).unwrap();
 
-    let modules = list_dir(stage, &modules_dir);
+    let modules = boot_modules(stage, config);
 
     if bootloader == "grub" {
+        if image_format != "iso" {
+            oops!(stage, "{} images need the limine bootloader", image_format);
+        }
+
         let grub_dir = format!("{}/boot/grub", &isofiles_dir);
         let grub_cfg = format!("{}/grub.cfg",  &grub_dir);
 
         try_create_dir(&grub_dir, true);
 
-        log!(stage, "generating grub.cfg");
-        let cfg_string = create_grub_cfg_string(&modules);
+        log!(stage, "generating grub.cfg from {}", grub_config);
+        let scope = template_scope(stage, config, &modules);
+        let cfg_string = render_config(stage, config, &grub_config, BUILTIN_GRUB_CFG, scope);
         write(&grub_cfg, &cfg_string).unwrap();
 
         log!(stage, "using grub-mkrescue to create an ISO file");
-        run(stage, &grub_mkrescue, &[&["-o", &iso, &isofiles_dir]]);
+        run_env(stage, &grub_mkrescue, &epoch_env, &[&["-o", &output, &isofiles_dir]]);
+
+    } else if bootloader == "limine" || bootloader == "uefi-dir" {
+        log!(stage, "compressing boot modules ({})", codec);
+        let archive_name = format!("modules.cpio{}", compression::extension(stage, &codec));
+        let archive_path = format!("{}/{}", &isofiles_dir, archive_name);
+
+        // archives of other codecs would end up in the image too
+        remove_archives(stage, &isofiles_dir);
 
-    } else if bootloader == "limine" {
-        log!(stage, "compressing boot modules");
-        let modules_cpio_lz4 = format!("{}/modules.cpio.lz4", &isofiles_dir);
         let mut opener = OpenOptions::new();
         let opener = opener.read(true);
 
         let cpio_entries = modules.iter()
-            .map(|(name, _)| {
+            .enumerate()
+            .map(|(i, name)| {
                 let path = format!("{}/{}", &modules_dir, name);
                 let file = opener.open(&path).unwrap();
-                (Builder::new(name), file)
+                // normalize everything the host would otherwise leak into the archive
+                let builder = Builder::new(name)
+                    .ino(i as u32 + 1)
+                    .mode(0o100644)
+                    .uid(0)
+                    .gid(0)
+                    .nlink(1)
+                    .mtime(epoch as u32);
+                (builder, file)
             });
 
-        let mut bytes = Vec::new();
-        // archive to a "newc" cpio in-memory file
-        write_cpio(cpio_entries, &mut bytes).unwrap();
+        // archive to a "newc" cpio file, compressing it on the fly;
+        // the lz4-block encoder reads back what it wrote, and buffers its output itself
+        let archive = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&archive_path);
+        let archive = match archive {
+            Ok(archive) => archive,
+            Err(e) => oops!(stage, "couldn't create {}: {}", archive_path, e),
+        };
+        let encoder = Encoder::new(stage, &codec, archive);
+        let encoder = match write_cpio(cpio_entries, encoder) {
+            Ok(encoder) => encoder,
+            Err(e) => oops!(stage, "couldn't write {}: {}", archive_path, e),
+        };
+
+        let uncompressed = encoder.consumed();
+        let archive = match encoder.finish() {
+            Ok(archive) => archive,
+            Err(e) => oops!(stage, "couldn't write {}: {}", archive_path, e),
+        };
+        let compressed = match archive.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => oops!(stage, "couldn't read {}: {}", archive_path, e),
+        };
 
-        // compress using LZ4, still in memory
-        let compressed = compress_prepend_size(&bytes);
+        let ratio = compressed as f64 / uncompressed.max(1) as f64;
+        log!(
+            stage, "{} modules: {} => {} ({:.1}%)",
+            modules.len(), human_size(uncompressed),
+            human_size(compressed), ratio * 100.0,
+        );
 
-        // write file
-        write(&modules_cpio_lz4, &compressed).unwrap();
+        let limine_dir = limine::provide(stage, config);
 
-        let prebuilt_subdir_exists = metadata(&prebuilt_subdir).is_ok();
-        if !prebuilt_subdir_exists {
-            log!(stage, "fetching limine pre-built binaries");
+        log!(stage, "generating limine.cfg from {}", limine_config);
 
-            let mut tarball_path = tarball_path;
-            let tarball_exists = metadata(&tarball_path).is_ok();
+        let scope = template_scope(stage, config, &modules).var("module-archive", &archive_name);
+        let config_contents = render_config(stage, config, &limine_config, BUILTIN_LIMINE_CFG, scope);
 
-            if tarball_exists {
-                // re-use it
-            } else if limine_tarball.starts_with("https://") {
-                let output_option = match downloader.as_str() {
-                    "wget" => "-O",
-                    "curl" => "-o",
-                    _ => oops!(stage, "unsupported downloader: {}; must be wget or curl.", &downloader),
-                };
+        let limine_cfg = format!("{}/limine.cfg", &isofiles_dir);
+        write(&limine_cfg, &config_contents).unwrap();
 
-                run(stage, &downloader, &[&[output_option, &tarball_path, &limine_tarball]]);
-            } else {
-                tarball_path = limine_tarball;
+        if bootloader == "uefi-dir" {
+            log!(stage, "populating the EFI system partition directory {}", output);
+
+            // don't leave files of previous builds behind
+            remove_esp_dir(stage, config, &output);
+
+            for (path, src) in esp_files(stage, config, &limine_dir, &archive_name, true) {
+                let dst = Path::new(&output).join(&path);
+                let parent = dst.parent().unwrap();
+                if let Err(e) = create_dir_all(parent) {
+                    oops!(stage, "couldn't create {}: {}", parent.display(), e);
+                }
+                if let Err(e) = copy(&src, &dst) {
+                    oops!(stage, "couldn't copy {} to {}: {}", src.display(), dst.display(), e);
+                }
             }
 
-            log!(stage, "extracting limine pre-built binaries");
-
-            try_create_dir(&prebuilt_dir, false);
+            let marker = Path::new(&output).join(ESP_DIR_MARKER);
+            if let Err(e) = write(&marker, "") {
+                oops!(stage, "couldn't write {}: {}", marker.display(), e);
+            }
 
-            run(stage, "tar", &[&["-axf", &tarball_path, "-C", &prebuilt_dir]]);
+            return;
         }
 
         log!(stage, "importing limine pre-built binaries");
 
         for import in [ "limine-cd.bin", "limine-cd-efi.bin", "limine.sys" ] {
-            let src = format!("{}/{}", &prebuilt_subdir, import);
+            let src = format!("{}/{}", &limine_dir, import);
             let dst = format!("{}/{}", &isofiles_dir, import);
 
             copy(&src, &dst).unwrap();
         }
 
-        log!(stage, "adding limine config: {}", limine_config);
+        // try to remove any existing image
+        let _ = remove_file(&output);
 
-        let config_contents = match limine_config.as_str() {
-            "built-in" => BUILTIN_LIMINE_CFG.into(),
-            path => read_to_string(path).unwrap(),
-        };
+        match (image_format.as_str(), iso_writer.as_str()) {
+            ("iso", "native") => {
+                log!(stage, "writing the image");
 
-        let limine_cfg = format!("{}/limine.cfg", &isofiles_dir);
-        write(&limine_cfg, &config_contents).unwrap();
+                let options = iso9660::Options {
+                    volume_id: config.str("add-bootloader.volume-id"),
+                    mtime: epoch,
+                    bios_boot: Some("limine-cd.bin".into()),
+                    efi_boot: Some("limine-cd-efi.bin".into()),
+                };
 
-        log!(stage, "politely asking {} to assembling the image", &xorriso);
+                if let Err(e) = iso9660::write_iso(Path::new(&isofiles_dir), Path::new(&output), &options) {
+                    oops!(stage, "{}", e);
+                }
+            },
+            ("iso", "xorriso") => {
+                log!(stage, "politely asking {} to assembling the image", &xorriso);
+
+                // xorriso also reads SOURCE_DATE_EPOCH for the volume dates and UUID
+                run_env(stage, &xorriso, &epoch_env, &[&[
+                    "-as", "mkisofs",
+                    "--set_all_file_dates", &format!("={}", epoch),
+                    "-uid", "0",
+                    "-gid", "0",
+                    "-V", &config.str("add-bootloader.volume-id"),
+                    "-b", "limine-cd.bin",
+                    "-no-emul-boot",
+                    "-boot-load-size", "4",
+                    "-boot-info-table",
+                    "--efi-boot", "limine-cd-efi.bin",
+                    "-efi-boot-part",
+                    "--efi-boot-image",
+                    "--protective-msdos-label",
+                    &isofiles_dir,
+                    "-o", &output,
+                ]]);
+            },
+            ("iso", _) => oops!(stage, "iso-writer must be \"native\" or \"xorriso\""),
+            ("raw-gpt" | "raw-mbr", _) => {
+                log!(stage, "writing the {} disk image", image_format);
+
+                let partition_table = match image_format.as_str() {
+                    "raw-gpt" => PartitionTable::Gpt,
+                    _ => PartitionTable::Mbr,
+                };
+                let options = disk_image::Options {
+                    partition_table,
+                    esp_size: config.int("add-bootloader.esp-size") as u64,
+                    label: config.str("add-bootloader.volume-id"),
+                    mtime: epoch,
+                };
+                let files = esp_files(stage, config, &limine_dir, &archive_name, false);
+
+                if let Err(e) = disk_image::write_disk_image(Path::new(&output), &files, &options) {
+                    oops!(stage, "{}", e);
+                }
+            },
+            _ => oops!(stage, "image-format must be \"iso\", \"raw-gpt\" or \"raw-mbr\""),
+        }
+
+        limine::deploy(stage, config, &limine_dir, &output);
+    } else {
+        oops!(stage, "unknown bootloader {}; must be \"grub\", \"limine\" or \"uefi-dir\"", &bootloader);
+    }
+}
 
-        // try to remove any existing iso
-        let _ = remove_file(&iso);
+/// Deletes the EFI system partition directory of a previous build, if it's one (see `is_own_esp_dir`).
+fn remove_esp_dir(stage: &str, config: &Config, output: &str) {
+    let info = match metadata(output) {
+        Ok(info) => info,
+        Err(_) => return,
+    };
 
-        run(stage, &xorriso, &[&[
-            "-as", "mkisofs",
-            "-b", "limine-cd.bin",
-            "-no-emul-boot",
-            "-boot-load-size", "4",
-            "-boot-info-table",
-            "--efi-boot", "limine-cd-efi.bin",
-            "-efi-boot-part",
-            "--efi-boot-image",
-            "--protective-msdos-label",
-            &isofiles_dir,
-            "-o", &iso,
-        ]]);
+    if !info.is_dir() {
+        oops!(stage, "output-esp-dir {} isn't a directory", output);
+    }
+    if !is_own_esp_dir(config, output) {
+        oops!(stage, "not deleting {}, which wasn't made by add-bootloader; remove it or change output-esp-dir", output);
+    }
 
-        log!(stage, "building limine-deploy");
+    if let Err(e) = remove_dir_all(output) {
+        oops!(stage, "couldn't remove {}: {}", output, e);
+    }
+}
 
-        run(stage, "make", &[&["-C", &prebuilt_subdir]]);
+/// Tells whether `path` was made by the "uefi-dir" bootloader, from its marker file, or is in
+/// `build-dir`. `output-esp-dir` may be set to a mounted partition, or to a mistyped path.
+pub fn is_own_esp_dir(config: &Config, path: &str) -> bool {
+    let build_dir = canonicalize(config.str("build-dir"));
+    let in_build_dir = match (canonicalize(path), build_dir) {
+        (Ok(path), Ok(build_dir)) => path != build_dir && path.starts_with(build_dir),
+        _ => false,
+    };
 
-        log!(stage, "running limine-deploy on the ISO");
+    in_build_dir || Path::new(path).join(ESP_DIR_MARKER).exists()
+}
 
-        let limine_deploy = format!("{}/limine-deploy", &prebuilt_subdir);
-        run(stage, &limine_deploy, &[&[&iso]]);
-    } else {
-        oops!(stage, "unknown bootloader {}; must be \"grub\" or \"limine\"", &bootloader);
+/// Removes the module archives (`modules.cpio*`) of previous builds from `isofiles_dir`.
+fn remove_archives(stage: &str, isofiles_dir: &str) {
+    let entries = match read_dir(isofiles_dir) {
+        Ok(entries) => entries,
+        Err(e) => oops!(stage, "couldn't list {}: {}", isofiles_dir, e),
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if entry.file_name().to_string_lossy().starts_with("modules.cpio") {
+            if let Err(e) = remove_file(entry.path()) {
+                oops!(stage, "couldn't remove {}: {}", entry.path().display(), e);
+            }
+        }
     }
 }
 
-// Creates string to write to grub.cfg file by looking through all files in input_directory
-fn create_grub_cfg_string(modules: &[(String, bool)]) -> String {
-    let mut lines = String::new();
-    
-    lines.push_str("### This file has been autogenerated, do not manually modify it!\n");
-    lines.push_str("set timeout=0\n");
-    lines.push_str("set default=0\n\n");
-    lines.push_str("menuentry \"Theseus OS\" {\n");
-    lines.push_str("\tmultiboot2 /boot/kernel.bin \n");
-
-    for (name, _is_dir) in modules {
-        lines.push_str(&format!("\tmodule2 /modules/{0:50}\t\t{1:50}\n", name, name));
+/// The image made by this stage: `output-iso` or, for raw disk images, `output-image`;
+/// with the "uefi-dir" bootloader, the `output-esp-dir` directory.
+pub fn output_path(stage: &str, config: &Config) -> String {
+    if config.str("add-bootloader.bootloader") == "uefi-dir" {
+        return config.str("output-esp-dir");
     }
 
-    lines.push_str("\n\tboot\n}\n");
-    lines
+    match config.str("add-bootloader.image-format").as_str() {
+        "iso" => config.str("output-iso"),
+        "raw-gpt" | "raw-mbr" => config.str("output-image"),
+        _ => oops!(stage, "image-format must be \"iso\", \"raw-gpt\" or \"raw-mbr\""),
+    }
+}
+
+/// Lists the contents of EFI system partitions, as paths in
+/// the partition mapped to files of the host.
+///
+/// It holds what limine needs, on UEFI systems and unless `uefi_only`
+/// is set on BIOS systems, and what limine.cfg refers to: the kernel
+/// and the module archive.
+fn esp_files(stage: &str, config: &Config, limine_dir: &str, archive_name: &str, uefi_only: bool) -> Vec<(String, PathBuf)> {
+    let isofiles_dir = config.str("directories.isofiles");
+    let nanocore_dst = config.str("add-bootloader.nanocore-destination");
+
+    let kernel = match Path::new(&nanocore_dst).strip_prefix(&isofiles_dir) {
+        Ok(path) => path.iter().map(|part| part.to_str().unwrap()).collect::<Vec<_>>().join("/"),
+        Err(_) => oops!(stage, "{} is outside of {}", nanocore_dst, isofiles_dir),
+    };
+
+    let mut files = vec![
+        ("limine.cfg".to_string(), PathBuf::from(format!("{}/limine.cfg", isofiles_dir))),
+        (kernel, PathBuf::from(&nanocore_dst)),
+        (archive_name.to_string(), PathBuf::from(format!("{}/{}", isofiles_dir, archive_name))),
+    ];
+    if !uefi_only {
+        files.push(("limine.sys".to_string(), PathBuf::from(format!("{}/limine.sys", limine_dir))));
+    }
+
+    // the path where UEFI firmware looks for a bootloader on removable media
+    let efi_name = match config.str("arch").as_str() {
+        "x86_64" => "BOOTX64.EFI",
+        "aarch64" => "BOOTAA64.EFI",
+        arch => oops!(stage, "no UEFI boot file name for {}", arch),
+    };
+    let efi_binary = PathBuf::from(format!("{}/{}", limine_dir, efi_name));
+    match efi_binary.exists() {
+        true => files.push((format!("EFI/BOOT/{}", efi_name), efi_binary)),
+        false if uefi_only => oops!(stage, "{} is missing from the limine directory", efi_name),
+        false => log!(stage, "warning: {} is missing; the image won't boot on UEFI systems", efi_binary.display()),
+    }
+
+    files
+}
+
+/// Lists the boot modules, as paths relative to the modules directory,
+/// according to `add-bootloader.module-selection`.
+///
+/// Other files are reported and left out. Modules are sorted, as the
+/// order of directory listings depends on the filesystem.
+pub fn boot_modules(stage: &str, config: &Config) -> Vec<String> {
+    let modules_dir = config.str("directories.modules");
+    let selection = config.str("add-bootloader.module-selection");
+
+    let mut files = Vec::new();
+    for entry in WalkDir::new(&modules_dir) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => oops!(stage, "couldn't list {}: {}", modules_dir, e),
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path().strip_prefix(&modules_dir).unwrap();
+        match path.iter().map(|part| part.to_str()).collect::<Option<Vec<_>>>() {
+            Some(components) => files.push(components.join("/")),
+            None => log!(stage, "warning: excluding {}, whose name isn't valid UTF-8", entry.path().display()),
+        }
+    }
+    files.sort();
+
+    let is_module: Box<dyn Fn(&str) -> bool> = match selection.as_str() {
+        "prefixes" => {
+            let prefixes = [
+                config.str("prefixes.kernel"),
+                config.str("prefixes.applications"),
+                config.str("prefixes.executables"),
+            ];
+            Box::new(move |path: &str| {
+                let name = path.rsplit('/').next().unwrap();
+                prefixes.iter().any(|prefix| name.starts_with(prefix.as_str()))
+            })
+        },
+        "manifest" => {
+            let manifest_path = config.str("copy-crate-objects.modules-manifest");
+            let serialized = match read_to_string(&manifest_path) {
+                Ok(serialized) => serialized,
+                Err(e) => oops!(stage, "couldn't read {} (did copy-crate-objects run?): {}", manifest_path, e),
+            };
+            let manifest: HashSet<String> = match serde_json::from_str(&serialized) {
+                Ok(manifest) => manifest,
+                Err(e) => oops!(stage, "couldn't parse {} (run copy-crate-objects again to rewrite it): {}", manifest_path, e),
+            };
+
+            let missing = manifest.iter().filter(|name| !files.contains(name)).collect::<Vec<_>>();
+            if !missing.is_empty() {
+                oops!(stage, "modules listed in {} are missing: {:?}", manifest_path, missing);
+            }
+
+            Box::new(move |path: &str| manifest.contains(path))
+        },
+        _ => oops!(stage, "add-bootloader.module-selection must be \"prefixes\" or \"manifest\""),
+    };
+
+    let (modules, strays): (Vec<_>, Vec<_>) = files.into_iter().partition(|path| is_module(path));
+
+    for stray in &strays {
+        log!(stage, "warning: {}/{} is not a module; leaving it out", modules_dir, stray);
+    }
+
+    modules
+}
+
+/// Returns the timestamp to use for all files in the image:
+/// `SOURCE_DATE_EPOCH` if it's set, `add-bootloader.mtime` otherwise.
+pub fn source_date_epoch(stage: &str, config: &Config) -> u64 {
+    let epoch = var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| config.str("add-bootloader.mtime"));
+    match epoch.parse() {
+        Ok(epoch) => epoch,
+        Err(_) => oops!(stage, "invalid timestamp {:?}; must be a number of seconds since 1970", epoch),
+    }
+}
+
+/// Gathers the variables available to bootloader configuration templates.
+fn template_scope(stage: &str, config: &Config, modules: &[String]) -> Scope {
+    let isofiles_dir = config.str("directories.isofiles");
+    let modules_dir = config.str("directories.modules");
+    let nanocore_dst = config.str("add-bootloader.nanocore-destination");
+    let timeout = config.int("add-bootloader.timeout");
+    let default_entry = config.str("add-bootloader.default-entry");
+    let entry_names = config.vec("add-bootloader.menu-entries");
+
+    // paths as seen by the bootloader, from the root of the image
+    let image_path = |path: &str| match Path::new(path).strip_prefix(&isofiles_dir) {
+        Ok(path) => format!("/{}", path.display()),
+        Err(_) => oops!(stage, "{} is outside of {}", path, isofiles_dir),
+    };
+
+    let defined_entries = opt(config.as_ref(), "add-bootloader.menu");
+    let default_entries = opt_default("add-bootloader.menu");
+    let entries = entry_names.iter()
+        .enumerate()
+        .map(|(index, name)| {
+            if defined_entries.get(name).is_none() && default_entries.get(name).is_none() {
+                oops!(stage, "unknown menu entry {}; add it to add-bootloader.menu", name);
+            }
+            Scope::new()
+                .var("name", name)
+                .var("index", index)
+                .var("title", config.str(&format!("add-bootloader.menu.{}.title", name)))
+                .var("cmdline", config.str(&format!("add-bootloader.menu.{}.cmdline", name)))
+        })
+        .collect();
+
+    let default_index = match entry_names.iter().position(|name| *name == default_entry) {
+        Some(index) => index,
+        None => oops!(stage, "default-entry {} isn't one of add-bootloader.menu-entries", default_entry),
+    };
+
+    let modules_path = image_path(&modules_dir);
+    let modules = modules.iter()
+        .map(|name| Scope::new().var("name", name).var("path", format!("{}/{}", modules_path, name)))
+        .collect();
+
+    Scope::new()
+        .var("kernel", image_path(&nanocore_dst))
+        .var("timeout", timeout)
+        .var("default-index", default_index)
+        .list("entries", entries)
+        .list("modules", modules)
+}
+
+/// Renders the configuration template at `path`, or `built_in` if `path` is "built-in".
+fn render_config(stage: &str, config: &Config, path: &str, built_in: &str, scope: Scope) -> String {
+    let template = match path {
+        "built-in" => built_in.to_string(),
+        path => match read_to_string(path) {
+            Ok(template) => template,
+            Err(e) => oops!(stage, "couldn't read {}: {}", path, e),
+        },
+    };
+
+    render(stage, &template, &scope, config)
 }
@@ -1,8 +1,50 @@
 use crate::log;
 use crate::oops;
 use crate::Config;
-use crate::run_env;
+use crate::opt;
+use crate::check_result;
+use crate::metadata::cargo_metadata;
 
+use std::fs::write;
+use std::fs::canonicalize;
+use std::fs::read_to_string;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A library built by cargo, as recorded in `build-cells.artifacts-manifest`.
+#[derive(Serialize, Deserialize)]
+pub struct Artifact {
+    pub package_id: String,
+    /// The name of the crate, with underscores instead of hyphens
+    pub crate_name: String,
+    /// The object file emitted by rustc (`--emit=obj`) or by `relink-rlibs`;
+    /// it may not exist.
+    pub object: PathBuf,
+    pub rmeta: PathBuf,
+    pub rlib: PathBuf,
+}
+
+/// The parts of cargo's JSON messages which are needed to locate artifacts.
+#[derive(Deserialize)]
+struct Message {
+    reason: String,
+    package_id: Option<String>,
+    target: Option<MessageTarget>,
+    #[serde(default)]
+    filenames: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct MessageTarget {
+    name: String,
+    kind: Vec<String>,
+}
 
 pub fn process(config: &Config) {
     let stage = "build-cells";
@@ -16,22 +58,184 @@ pub fn process(config: &Config) {
     let manifest_path = config.str("build-cells.manifest-path");
     let cargo_flags = config.vec("build-cells.cargo-flags");
     let rust_flags = config.vec("build-cells.rust-flags").join(" ");
+    let artifacts_manifest = config.str("build-cells.artifacts-manifest");
 
-    if !["debug", "release"].contains(&build_mode.as_str()) {
-        oops!(stage, "build-mode must be \"debug\" or \"release\"");
+    let valid_profile_name = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if build_mode.is_empty() || !build_mode.chars().all(valid_profile_name) {
+        oops!(stage, "build-mode must be the name of a cargo profile, e.g. \"debug\" or \"release\"");
     }
 
+    let features = selected_features(stage, config);
+
     log!(stage, "building all crates using cargo");
 
-    run_env(stage, &cargo, &[("RUSTFLAGS", &rust_flags)], &[
-        &[
-            &format!("+{}", &toolchain),
-            "build",
-            &format!("--manifest-path={}", &manifest_path),
-            &format!("--{}", &build_mode),
-            "--target-dir", &format!("{}", &target_dir),
-            "--target", &target,
-        ],
-        &cargo_flags.iter().map(|f| f.as_str()).collect::<Vec<_>>(),
-    ]);
-}
\ No newline at end of file
+    let mut command = Command::new(&cargo);
+    command
+        .env("RUSTFLAGS", &rust_flags)
+        .arg(format!("+{}", &toolchain))
+        .arg("build")
+        .arg(format!("--manifest-path={}", &manifest_path))
+        .args(["--profile", cargo_profile(&build_mode)])
+        .args(["--target-dir", &target_dir])
+        .args(["--target", &target])
+        // diagnostics are still rendered on stderr
+        .arg("--message-format=json-render-diagnostics")
+        .args(features.iter().flat_map(|features| [ "--features", features ]))
+        .args(&cargo_flags)
+        .stdout(Stdio::piped());
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => oops!(stage, "failed to start {}: {}", &cargo, e),
+    };
+
+    let mut artifacts = Vec::new();
+
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    for line in stdout.lines() {
+        let line = line.unwrap();
+        match serde_json::from_str::<Message>(&line) {
+            Ok(message) => artifacts.extend(parse_artifact(message)),
+            // not a cargo message; most likely printed by a build script
+            Err(_) => println!("{}", line),
+        }
+    }
+
+    check_result(stage, child.wait(), &cargo);
+
+    log!(stage, "recording {} artifacts in {}", artifacts.len(), artifacts_manifest);
+
+    let serialized = serde_json::to_string_pretty(&artifacts).unwrap();
+    write(&artifacts_manifest, serialized).unwrap();
+}
+
+/// Gathers the features selected in `build-cells.features` and checks
+/// that they exist in the workspace, reporting all unknown ones at once.
+///
+/// Returns the features as a comma-separated list, if any.
+fn selected_features(stage: &str, config: &Config) -> Option<String> {
+    let mut features = config.vec("build-cells.features.global");
+    features.extend(config.vec("build-cells.features.packages"));
+
+    let sets = config.vec("build-cells.features.sets");
+    let defined_sets = opt(config.as_ref(), "build-cells.feature-sets");
+    for set in &sets {
+        if defined_sets.get(set).is_none() {
+            oops!(stage, "unknown feature set {}; add it to build-cells.feature-sets", set);
+        }
+        features.extend(config.vec(&format!("build-cells.feature-sets.{}", set)));
+    }
+
+    if features.is_empty() {
+        return None;
+    }
+
+    log!(stage, "checking features: {}", features.join(", "));
+
+    let metadata = cargo_metadata(stage, config);
+    let mut unknown = Vec::new();
+
+    // un-namespaced features are those of the package at `manifest-path` (none for a virtual manifest)
+    let manifest_path = PathBuf::from(config.str("build-cells.manifest-path"));
+    let manifest_path = canonicalize(&manifest_path).unwrap_or(manifest_path);
+    let root_package = metadata.packages.iter().find(|p| {
+        canonicalize(&p.manifest_path).is_ok_and(|path| path == manifest_path)
+    });
+
+    for feature in &features {
+        let known = match feature.split_once('/') {
+            Some((package, feature)) => metadata.packages.iter()
+                .find(|p| p.name == package)
+                .is_some_and(|p| p.has_feature(feature)),
+            None => root_package.is_some_and(|p| p.has_feature(feature)),
+        };
+
+        if !known {
+            unknown.push(feature.as_str());
+        }
+    }
+
+    if !unknown.is_empty() {
+        if root_package.is_none() && unknown.iter().any(|feature| !feature.contains('/')) {
+            log!(stage, "{} is a virtual manifest; name the package of each feature (package/feature)", manifest_path.display());
+        }
+        oops!(stage, "unknown features: {}", unknown.join(", "));
+    }
+
+    features.sort();
+    features.dedup();
+    Some(features.join(","))
+}
+
+/// Maps a `build-mode` to the name of the corresponding cargo profile.
+///
+/// "debug" is accepted as an alias for cargo's "dev" profile.
+pub fn cargo_profile(build_mode: &str) -> &str {
+    match build_mode {
+        "debug" => "dev",
+        profile => profile,
+    }
+}
+
+/// Maps a `build-mode` to the directory where cargo puts its artifacts,
+/// i.e. `target/<target>/<profile-dir>`.
+pub fn profile_dir(build_mode: &str) -> &str {
+    match cargo_profile(build_mode) {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+/// Extracts the paths of a library's files from a `compiler-artifact` message.
+///
+/// Proc macros, build scripts and binaries are ignored.
+fn parse_artifact(message: Message) -> Option<Artifact> {
+    if message.reason != "compiler-artifact" {
+        return None;
+    }
+
+    let target = message.target?;
+    let is_lib = target.kind.iter().any(|kind| kind == "lib" || kind == "rlib");
+    if !is_lib {
+        return None;
+    }
+
+    // Artifact names look like `deps/libcrate_name-0123456789abcdef.rlib`, but the rlib of
+    // workspace members is reported at its uplifted location (`libcrate_name.rlib`) instead.
+    // Their rmeta file is always reported in `deps`, with the hash.
+    let rlib_or_rmeta = message.filenames.iter().find(|path| {
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let in_deps = path.parent().and_then(|dir| dir.file_name()).is_some_and(|dir| dir == "deps");
+        in_deps && matches!(extension, Some("rlib") | Some("rmeta"))
+    })?;
+    let file_stem = rlib_or_rmeta.file_stem()?.to_str()?;
+    let object_stem = file_stem.strip_prefix("lib")?;
+
+    let object = rlib_or_rmeta.with_file_name(format!("{}.o", object_stem));
+    let rmeta = rlib_or_rmeta.with_extension("rmeta");
+    let rlib = rlib_or_rmeta.with_extension("rlib");
+
+    Some(Artifact {
+        package_id: message.package_id?,
+        crate_name: target.name.replace('-', "_"),
+        object,
+        rmeta,
+        rlib,
+    })
+}
+
+/// Reads the artifacts recorded during the last `build-cells` run.
+pub fn read_artifacts_manifest(stage: &str, config: &Config) -> Vec<Artifact> {
+    let path = config.str("build-cells.artifacts-manifest");
+
+    let serialized = match read_to_string(&path) {
+        Ok(serialized) => serialized,
+        Err(e) => oops!(stage, "couldn't read {} (did build-cells run?): {}", &path, e),
+    };
+
+    match serde_json::from_str(&serialized) {
+        Ok(artifacts) => artifacts,
+        Err(e) => oops!(stage, "failed to parse {}: {}", &path, e),
+    }
+}
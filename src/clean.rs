@@ -0,0 +1,131 @@
+use crate::log;
+use crate::oops;
+use crate::Config;
+use crate::try_create_dir;
+use crate::add_bootloader;
+
+use std::fs::metadata;
+use std::fs::remove_dir_all;
+use std::fs::remove_file;
+use std::path::Path;
+
+use walkdir::WalkDir;
+
+pub fn process(config: &Config) {
+    let stage = "clean";
+
+    let targets = config.vec("clean.targets");
+    let dry_run = config.bool("clean.dry-run");
+
+    let mut paths = Vec::new();
+    // emptied rather than removed: stages after `directories` expect them
+    let mut recreate = Vec::new();
+
+    for target in &targets {
+        let keys: &[&str] = match target.as_str() {
+            "target" => &[ "directories.target" ],
+            "modules" => &[ "directories.modules" ],
+            "bootloader" => &[ "add-bootloader.extract-dir", "add-bootloader.limine-deploy-cache" ],
+            // everything created by the `directories` stage, except
+            // the downloaded limine tarball which lives in build-dir
+            "all" => &[
+                "directories.nanocore",
+                "directories.isofiles",
+                "directories.modules",
+                "directories.deps",
+                "directories.extracted-rlibs",
+                "directories.debug-symbols",
+                "directories.target",
+                "add-bootloader.extract-dir",
+                "add-bootloader.limine-deploy-cache",
+                "output-iso",
+                "output-image",
+                "output-esp-dir",
+            ],
+            _ => oops!(stage, "unknown clean target \"{}\"; must be \"target\", \"modules\", \"bootloader\" or \"all\"", target),
+        };
+
+        paths.extend(keys.iter().map(|key| config.str(key)));
+
+        if target == "modules" {
+            recreate.push(config.str("directories.modules"));
+        }
+    }
+
+    // don't count (or remove) anything twice
+    paths.sort();
+    paths.dedup();
+    let paths = paths.iter()
+        .filter(|path| !paths.iter().any(|other| other != *path && Path::new(path).starts_with(other)))
+        .collect::<Vec<_>>();
+
+    let esp_dir = config.str("output-esp-dir");
+    let mut total = 0;
+
+    for path in paths {
+        let is_dir = match metadata(path) {
+            Ok(metadata) => metadata.is_dir(),
+            Err(_) => continue,
+        };
+
+        // as in add-bootloader, which only replaces it under the same conditions
+        if *path == esp_dir && is_dir && !add_bootloader::is_own_esp_dir(config, path) {
+            log!(stage, "warning: not removing {}, which wasn't made by add-bootloader", path);
+            continue;
+        }
+
+        let size = disk_usage(path);
+        total += size;
+
+        if dry_run {
+            println!("• {:50} {:>10}", path, human_size(size));
+        } else {
+            log!(stage, "removing {} ({})", path, human_size(size));
+
+            let result = match is_dir {
+                true => remove_dir_all(path),
+                _    => remove_file(path),
+            };
+
+            if let Err(e) = result {
+                oops!(stage, "failed to remove {}: {}", path, e);
+            }
+        }
+    }
+
+    if !dry_run {
+        for path in &recreate {
+            try_create_dir(path, true);
+        }
+    }
+
+    match dry_run {
+        true => log!(stage, "{} would be freed", human_size(total)),
+        _    => log!(stage, "freed {}", human_size(total)),
+    }
+}
+
+/// Sums the size of all files under `path`, which may also be a single file.
+fn disk_usage(path: &str) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+pub fn human_size(bytes: u64) -> String {
+    let units = [ "B", "KiB", "MiB", "GiB" ];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, units[0]),
+        _ => format!("{:.1} {}", size, units[unit]),
+    }
+}
@@ -0,0 +1,469 @@
+//! Codecs which the module archive can be compressed with.
+//!
+//! All of them are streamed: the archive never has to be held in memory.
+
+use crate::oops;
+
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+use lz4_flex::frame::FrameEncoder;
+use lz4_flex::frame::FrameInfo;
+
+pub const CODECS: &[&str] = &[ "none", "lz4-block", "lz4-frame", "zstd" ];
+
+/// The file extension of archives compressed with `codec`,
+/// which the kernel uses to detect the format.
+pub fn extension(stage: &str, codec: &str) -> &'static str {
+    match codec {
+        "none" => "",
+        // LZ4 block, prefixed with the uncompressed size (little-endian u32)
+        "lz4-block" => ".lz4",
+        "lz4-frame" => ".lz4f",
+        "zstd" => ".zst",
+        _ => oops!(stage, "unknown codec {}; must be one of: {}", codec, CODECS.join(", ")),
+    }
+}
+
+/// Compresses `bytes` in memory.
+pub fn compress(stage: &str, codec: &str, bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new(stage, codec, Cursor::new(Vec::new()));
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap().into_inner()
+}
+
+enum Codec<W: Read + Write + Seek> {
+    None(W),
+    Lz4Block(Lz4BlockEncoder<W>),
+    Lz4Frame(FrameEncoder<W>),
+    Zstd(zstd::stream::Encoder<'static, W>),
+}
+
+/// Compresses everything written to it into `W`.
+///
+/// `finish` must be called once all data is written.
+pub struct Encoder<W: Read + Write + Seek> {
+    codec: Codec<W>,
+    consumed: u64,
+}
+
+impl<W: Read + Write + Seek> Encoder<W> {
+    pub fn new(stage: &str, codec: &str, inner: W) -> Self {
+        let codec = match codec {
+            "none" => Codec::None(inner),
+            "lz4-block" => Codec::Lz4Block(Lz4BlockEncoder::new(inner).unwrap()),
+            "lz4-frame" => {
+                let mut frame_info = FrameInfo::new();
+                frame_info.block_checksums = true;
+                frame_info.content_checksum = true;
+                Codec::Lz4Frame(FrameEncoder::with_frame_info(frame_info, inner))
+            },
+            "zstd" => Codec::Zstd(zstd::stream::Encoder::new(inner, zstd::DEFAULT_COMPRESSION_LEVEL).unwrap()),
+            _ => oops!(stage, "unknown codec {}; must be one of: {}", codec, CODECS.join(", ")),
+        };
+
+        Self {
+            codec,
+            consumed: 0,
+        }
+    }
+
+    /// The number of uncompressed bytes written so far.
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Writes the end of the compressed stream, then flushes `W`.
+    pub fn finish(self) -> io::Result<W> {
+        let mut inner = match self.codec {
+            Codec::None(inner) => inner,
+            Codec::Lz4Block(encoder) => encoder.finish()?,
+            Codec::Lz4Frame(encoder) => encoder.finish().map_err(io::Error::other)?,
+            Codec::Zstd(encoder) => encoder.finish()?,
+        };
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Read + Write + Seek> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = match &mut self.codec {
+            Codec::None(inner) => inner.write(buf)?,
+            Codec::Lz4Block(encoder) => encoder.write(buf)?,
+            Codec::Lz4Frame(encoder) => encoder.write(buf)?,
+            Codec::Zstd(encoder) => encoder.write(buf)?,
+        };
+        self.consumed += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.codec {
+            Codec::None(inner) => inner.flush(),
+            Codec::Lz4Block(encoder) => encoder.flush(),
+            Codec::Lz4Frame(encoder) => encoder.flush(),
+            Codec::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Matches can refer to at most this many bytes back
+const WINDOW: usize = 0xffff;
+/// Input is encoded once this many bytes are pending, and runs of literals
+/// are written out once they are this long
+const CHUNK: usize = 1 << 20;
+/// The last match must start at least this many bytes before the end of the block
+const MF_LIMIT: usize = 12;
+/// The last bytes of a block are always literals
+const LAST_LITERALS: usize = 5;
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 16;
+
+/// Streaming encoder producing a single LZ4 block prefixed with its
+/// uncompressed size, as `lz4_flex::block::compress_prepend_size` does.
+///
+/// The size is written once the input is complete, which is why `W` must be seekable.
+/// Memory use is bounded by the window and the chunk size: a sequence's literals
+/// come after their length, so long runs of literals are written out ahead of it,
+/// and moved once the length is known, which is why `W` must also be readable.
+struct Lz4BlockEncoder<W: Read + Write + Seek> {
+    inner: W,
+    /// Where the uncompressed size goes
+    start: u64,
+    /// The window followed by the bytes which weren't encoded yet
+    buffer: Vec<u8>,
+    /// Position of `buffer[0]` in the input
+    offset: u64,
+    /// Index in `buffer` of the first literal of the next sequence which wasn't written out
+    anchor: usize,
+    /// Index in `buffer` of the next position to look for a match at
+    cursor: usize,
+    /// Hash of 4 bytes => position in the input + 1, or 0
+    table: Vec<u64>,
+    /// Encoded bytes which weren't written to `inner` yet
+    output: Vec<u8>,
+    /// Literals of the next sequence already written out: (position of its token in `inner`, count)
+    spilled: Option<(u64, u64)>,
+}
+
+impl<W: Read + Write + Seek> Lz4BlockEncoder<W> {
+    fn new(mut inner: W) -> io::Result<Self> {
+        let start = inner.stream_position()?;
+        inner.write_all(&[0; 4])?;
+
+        Ok(Self {
+            inner,
+            start,
+            buffer: Vec::new(),
+            offset: 0,
+            anchor: 0,
+            cursor: 0,
+            table: vec![0; 1 << HASH_BITS],
+            output: Vec::new(),
+            spilled: None,
+        })
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.encode()?;
+
+        // the last sequence only has literals
+        let end = self.buffer.len();
+        self.write_literals(end, 0)?;
+        self.write_output()?;
+
+        let size = self.offset + self.buffer.len() as u64;
+        let size = match u32::try_from(size) {
+            Ok(size) => size,
+            Err(_) => return Err(io::Error::other("LZ4 blocks are limited to 4 GiB")),
+        };
+
+        let end = self.inner.stream_position()?;
+        self.inner.seek(SeekFrom::Start(self.start))?;
+        self.inner.write_all(&size.to_le_bytes())?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+
+    /// Emits sequences for the buffered input, as if the block ended with it.
+    fn encode(&mut self) -> io::Result<()> {
+        let end = self.buffer.len();
+
+        while self.cursor + MF_LIMIT <= end {
+            let cursor = self.cursor;
+            let position = self.offset + cursor as u64;
+            let bytes = read_u32(&self.buffer, cursor);
+
+            let hash = (bytes.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+            let candidate = self.table[hash];
+            self.table[hash] = position + 1;
+
+            let candidate = match candidate.checked_sub(1) {
+                Some(candidate) if candidate >= self.offset && position - candidate <= WINDOW as u64 => {
+                    (candidate - self.offset) as usize
+                },
+                _ => {
+                    self.cursor += 1;
+                    continue;
+                },
+            };
+
+            if read_u32(&self.buffer, candidate) != bytes {
+                self.cursor += 1;
+                continue;
+            }
+
+            let mut length = MIN_MATCH;
+            while cursor + length < end - LAST_LITERALS && self.buffer[candidate + length] == self.buffer[cursor + length] {
+                length += 1;
+            }
+
+            self.write_sequence(cursor - candidate, length)?;
+            self.cursor += length;
+            self.anchor = self.cursor;
+        }
+
+        Ok(())
+    }
+
+    fn write_sequence(&mut self, distance: usize, length: usize) -> io::Result<()> {
+        let length = length - MIN_MATCH;
+
+        self.write_literals(self.cursor, length.min(15) as u8)?;
+        self.output.extend_from_slice(&(distance as u16).to_le_bytes());
+        if length >= 15 {
+            write_length(&mut self.output, length - 15)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the token and the literals (up to `end`) of a sequence.
+    fn write_literals(&mut self, end: usize, match_length: u8) -> io::Result<()> {
+        let (token_position, spilled) = match self.spilled.take() {
+            Some(spilled) => spilled,
+            None => {
+                let literals = end - self.anchor;
+                self.output.push(((literals.min(15) as u8) << 4) | match_length);
+                if literals >= 15 {
+                    write_length(&mut self.output, literals - 15)?;
+                }
+                self.output.extend_from_slice(&self.buffer[self.anchor..end]);
+                return Ok(());
+            },
+        };
+
+        // make room for the length between the token and the literals written out
+        let literals = spilled + (end - self.anchor) as u64;
+        let mut header = vec![ (15 << 4) | match_length ];
+        write_length(&mut header, (literals - 15) as usize)?;
+
+        self.write_output()?;
+        let literals_position = token_position + 1;
+        self.move_forward(literals_position, spilled, header.len() as u64 - 1)?;
+        self.inner.seek(SeekFrom::Start(token_position))?;
+        self.inner.write_all(&header)?;
+        self.inner.seek(SeekFrom::Start(literals_position + header.len() as u64 - 1 + spilled))?;
+
+        self.output.extend_from_slice(&self.buffer[self.anchor..end]);
+        Ok(())
+    }
+
+    /// Writes out the literals before the cursor, which can't be part of a match anymore.
+    fn spill_literals(&mut self) -> io::Result<()> {
+        self.write_output()?;
+
+        let (token_position, spilled) = match self.spilled {
+            Some(spilled) => spilled,
+            None => {
+                // the token is written along with the length
+                let token_position = self.inner.stream_position()?;
+                self.inner.write_all(&[0])?;
+                (token_position, 0)
+            },
+        };
+
+        self.inner.write_all(&self.buffer[self.anchor..self.cursor])?;
+        self.spilled = Some((token_position, spilled + (self.cursor - self.anchor) as u64));
+        self.anchor = self.cursor;
+        Ok(())
+    }
+
+    /// Moves `length` bytes of `inner` at `position` forward by `distance` bytes.
+    fn move_forward(&mut self, position: u64, length: u64, distance: u64) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK];
+        let mut remaining = length;
+
+        // the end first, as the destination overlaps the source
+        while remaining > 0 {
+            let size = remaining.min(CHUNK as u64);
+            remaining -= size;
+            let chunk = &mut chunk[..size as usize];
+            self.inner.seek(SeekFrom::Start(position + remaining))?;
+            self.inner.read_exact(chunk)?;
+            self.inner.seek(SeekFrom::Start(position + remaining + distance))?;
+            self.inner.write_all(chunk)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_output(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.output)?;
+        self.output.clear();
+        Ok(())
+    }
+
+    /// Drops the bytes which can't be referred to anymore.
+    fn compact(&mut self) {
+        let discarded = self.anchor.min(self.cursor.saturating_sub(WINDOW));
+        self.buffer.drain(..discarded);
+        self.offset += discarded as u64;
+        self.anchor -= discarded;
+        self.cursor -= discarded;
+    }
+}
+
+impl<W: Read + Write + Seek> Write for Lz4BlockEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+
+        // Matches can't extend past the end of the buffered input,
+        // so waiting for more input makes them longer.
+        if self.buffer.len() - self.cursor >= CHUNK {
+            self.encode()?;
+            if self.cursor - self.anchor >= CHUNK {
+                self.spill_literals()?;
+            }
+            if self.output.len() >= CHUNK {
+                self.write_output()?;
+            }
+            self.compact();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_output()?;
+        self.inner.flush()
+    }
+}
+
+fn read_u32(bytes: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(bytes[index..index + 4].try_into().unwrap())
+}
+
+/// Writes the remainder of a literal or match length which didn't fit in a token.
+fn write_length<W: Write>(inner: &mut W, mut length: usize) -> io::Result<()> {
+    while length >= 255 {
+        inner.write_all(&[255])?;
+        length -= 255;
+    }
+    inner.write_all(&[length as u8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lz4_flex::block::decompress_size_prepended;
+
+    /// Deterministic, incompressible bytes (xorshift64)
+    fn random(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    /// Compresses `input` in `piece`-sized writes, checks that it decompresses back.
+    fn round_trip(input: &[u8], piece: usize) -> Vec<u8> {
+        let mut encoder = Lz4BlockEncoder::new(Cursor::new(Vec::new())).unwrap();
+        for piece in input.chunks(piece.max(1)) {
+            encoder.write_all(piece).unwrap();
+            assert!(encoder.buffer.len() <= WINDOW + 2 * CHUNK + piece.len());
+        }
+        let compressed = encoder.finish().unwrap().into_inner();
+
+        let decompressed = decompress_size_prepended(&compressed).unwrap();
+        assert!(decompressed == input, "round trip of {} bytes failed", input.len());
+        compressed
+    }
+
+    #[test]
+    fn empty() {
+        round_trip(&[], 1);
+    }
+
+    #[test]
+    fn shorter_than_mf_limit() {
+        for len in 1..=MF_LIMIT {
+            round_trip(&vec![b'a'; len], 1);
+            round_trip(&random(len, len as u64), 3);
+        }
+    }
+
+    #[test]
+    fn incompressible() {
+        let input = random(3 * CHUNK + 12345, 1);
+        let compressed = round_trip(&input, 1 << 16);
+        assert!(compressed.len() < input.len() + input.len() / 200 + 32);
+    }
+
+    #[test]
+    fn long_repeats() {
+        let mut input = vec![0; 5 * CHUNK / 2];
+        input.extend(random(1000, 2).repeat(3000));
+        input.extend(b"theseus".repeat(100_000));
+        let compressed = round_trip(&input, 4096);
+        assert!(compressed.len() < input.len() / 50);
+    }
+
+    #[test]
+    fn larger_than_window_and_chunk() {
+        // repeats which are just inside and just outside the window, in between runs of literals
+        let block = random(WINDOW - 100, 3);
+        let mut input = Vec::new();
+        for seed in 0..8 {
+            input.extend(&block);
+            input.extend(random(CHUNK / 3 + seed * 1000, seed as u64 + 4));
+            input.extend(&block[..200]);
+        }
+        input.extend(random(CHUNK + 7, 20));
+        input.extend(&block);
+        round_trip(&input, 100_000);
+        round_trip(&input, input.len());
+    }
+
+    #[test]
+    fn every_codec() {
+        let mut input = random(100_000, 5);
+        input.extend(input.clone());
+
+        for codec in CODECS {
+            let compressed = compress("test", codec, &input);
+            let decompressed = match *codec {
+                "none" => compressed,
+                "lz4-block" => decompress_size_prepended(&compressed).unwrap(),
+                "lz4-frame" => {
+                    let mut decompressed = Vec::new();
+                    lz4_flex::frame::FrameDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+                    decompressed
+                },
+                "zstd" => zstd::decode_all(&compressed[..]).unwrap(),
+                _ => unreachable!(),
+            };
+            assert!(decompressed == input, "round trip through {} failed", codec);
+        }
+    }
+}
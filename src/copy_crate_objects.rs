@@ -3,6 +3,10 @@ use crate::oops;
 use crate::Config;
 use crate::list_dir;
 use crate::try_create_dir;
+use crate::metadata::cargo_metadata;
+use crate::build_cells::read_artifacts_manifest;
+use crate::build_cells::Artifact;
+use crate::crate_graph::CrateGraph;
 
 use std::io::Error;
 use std::io::ErrorKind;
@@ -12,8 +16,8 @@ use std::io::BufRead;
 use std::fs::canonicalize;
 use std::fs::read_dir;
 use std::fs::remove_file;
-use std::fs::DirEntry;
 use std::fs::copy;
+use std::fs::write;
 use std::fs::File;
 use std::path::Path;
 use std::path::PathBuf;
@@ -32,15 +36,16 @@ pub fn process(config: &Config) {
     let modules_dir = config.str("directories.modules");
     let deps_dir = config.str("directories.deps");
     let sysroot_dir = config.str("directories.sysroot");
-    let kernel_path = config.str("directories.kernel");
-    let apps_path = config.str("directories.apps");
 
     let kernel_prefix = config.str("prefixes.kernel");
     let apps_prefix = config.str("prefixes.applications");
 
     let target_deps_dirs = config.vec("copy-crate-objects.target-dirs");
-    let extra_apps = config.vec("copy-crate-objects.extra-apps");
+    let extra_target_dirs = config.vec("copy-crate-objects.extra-target-dirs");
+    let artifacts = config.str("copy-crate-objects.artifacts");
     let debug_crates_objects = config.bool("copy-crate-objects.debug-crate-objects");
+    let modules_manifest = config.str("copy-crate-objects.modules-manifest");
+    let nanocore_syms = config.str("serialize-nanocore-syms.output-path");
 
     log!(stage, "removing previous objects");
 
@@ -52,61 +57,83 @@ pub fn process(config: &Config) {
 
     log!(stage, "discovering crates");
 
-    let kernel_path_buf = match canonicalize(&kernel_path) {
-        Ok(path_buf) => path_buf,
-        _ => oops!(stage, "couldn't access {}", &kernel_path),
-    };
-
-    let kernel_crates_set = match kernel_path_buf.is_file() {
-        true => populate_crates_from_file(kernel_path_buf),
-        _    => populate_crates_from_dir(kernel_path_buf),
-    }.unwrap_or_else(|e| oops!(stage, "couldn't access {}: {}", &kernel_path, e));
+    let (kernel_crates_set, apps_crates_set) = classify_crates(stage, config);
 
-    let apps_path_buf = match canonicalize(&apps_path) {
-        Ok(path_buf) => path_buf,
-        _ => oops!(stage, "couldn't access {}", &apps_path),
+    let (
+        mut app_object_files,
+        mut kernel_objects_and_deps_files,
+        other_objects_and_deps_files,
+    ) = match artifacts.as_str() {
+        "manifest" => {
+            // objects which weren't built by the last `build-cells` run are still found by scanning
+            let mut found = parse_input_dir(
+                &apps_crates_set,
+                &kernel_crates_set,
+                &extra_target_dirs,
+            ).unwrap();
+
+            let artifacts = read_artifacts_manifest(stage, config);
+            parse_artifacts(&apps_crates_set, &kernel_crates_set, artifacts, &mut found);
+
+            found
+        },
+        "scan" => parse_input_dir(
+            &apps_crates_set,
+            &kernel_crates_set,
+            &[ target_deps_dirs, extra_target_dirs ].concat(),
+        ).unwrap(),
+        _ => oops!(stage, "copy-crate-objects.artifacts must be \"manifest\" or \"scan\""),
     };
 
-    let mut apps_crates_set = match apps_path_buf.is_file() {
-        true => populate_crates_from_file(apps_path_buf),
-        _    => populate_crates_from_dir(apps_path_buf),
-    }.unwrap_or_else(|e| oops!(stage, "couldn't access {}: {}", &apps_path, e));
-
-    apps_crates_set.extend(extra_apps);
+    if let Some((kernel_crates, app_crates)) = select_crates(stage, config, &kernel_crates_set, &apps_crates_set) {
+        log!(stage, "selected {} kernel crates and {} applications", kernel_crates.len(), app_crates.len());
+        kernel_objects_and_deps_files.retain(|name, _| kernel_crates.contains(name));
+        app_object_files.retain(|name, _| app_crates.contains(name));
+    }
 
-    let (
-        app_object_files,
-        kernel_objects_and_deps_files,
-        other_objects_and_deps_files,
-    ) = parse_input_dir(
-        apps_crates_set,
-        kernel_crates_set,
-        target_deps_dirs,
-        debug_crates_objects,
-    ).unwrap();
+    // optional debug output
+    if debug_crates_objects {
+        println!("APPLICATION OBJECT FILES:");
+        print_crates_objects(&app_object_files, PRINT_SORTED);
+        println!("KERNEL OBJECT FILES AND DEPS FILES:");
+        print_crates_objects_and_deps(&kernel_objects_and_deps_files, PRINT_SORTED);
+        println!("OTHER OBJECT FILES AND DEPS FILES:");
+        print_crates_objects_and_deps(&other_objects_and_deps_files, PRINT_SORTED);
+    }
 
     log!(stage, "copying crate objects");
 
     // Now that we have obtained the lists of kernel, app, and other crates,
     // we copy their crate object files into the output object directory with the proper prefix.
-    copy_files(
+    let mut modules = copy_files(
         &modules_dir,
-        app_object_files.values().map(|d| d.path()),
+        app_object_files.values(),
         &apps_prefix,
         debug_crates_objects,
     ).unwrap();
-    copy_files(
+    modules.extend(copy_files(
         &modules_dir,
-        kernel_objects_and_deps_files.values().map(|(obj_direnty, _)| obj_direnty.path()),
+        kernel_objects_and_deps_files.values().map(|(obj_file, _)| obj_file),
         &kernel_prefix,
         debug_crates_objects,
-    ).unwrap();
-    copy_files(
+    ).unwrap());
+    modules.extend(copy_files(
         &modules_dir,
-        other_objects_and_deps_files.values().map(|(obj_direnty, _)| obj_direnty.path()),
+        other_objects_and_deps_files.values().map(|(obj_file, _)| obj_file),
         &kernel_prefix,
         debug_crates_objects,
-    ).unwrap();
+    ).unwrap());
+
+    // the nano_core's symbols are loaded as a module too
+    let nanocore_syms = Path::new(&nanocore_syms);
+    if nanocore_syms.parent() == Some(Path::new(&modules_dir)) {
+        modules.extend(nanocore_syms.file_name().and_then(|name| name.to_str()).map(String::from));
+    }
+
+    modules.sort();
+    log!(stage, "recording {} modules in {}", modules.len(), modules_manifest);
+    let serialized = serde_json::to_string_pretty(&modules).unwrap();
+    write(&modules_manifest, serialized).unwrap();
 
     // Now we do the same kind of copy operation of crate dependency files, namely the .rlib and .rmeta files,
     // into the output deps directory.
@@ -145,6 +172,190 @@ pub fn process(config: &Config) {
     ).unwrap();
 }
 
+/// Determines which crates are kernel crates and which are applications.
+///
+/// Returns the sets of kernel crate names and application crate names,
+/// the latter including `copy-crate-objects.extra-apps`.
+pub fn classify_crates(stage: &str, config: &Config) -> (HashSet<String>, HashSet<String>) {
+    let kernel_path = config.str("directories.kernel");
+    let apps_path = config.str("directories.apps");
+    let extra_apps = config.vec("copy-crate-objects.extra-apps");
+    let discovery = config.str("copy-crate-objects.crate-discovery");
+
+    let kernel_path_buf = match canonicalize(&kernel_path) {
+        Ok(path_buf) => path_buf,
+        _ => oops!(stage, "couldn't access {}", &kernel_path),
+    };
+
+    let apps_path_buf = match canonicalize(&apps_path) {
+        Ok(path_buf) => path_buf,
+        _ => oops!(stage, "couldn't access {}", &apps_path),
+    };
+
+    let use_metadata = match discovery.as_str() {
+        "cargo-metadata" => true,
+        "directories" => false,
+        _ => oops!(stage, "copy-crate-objects.crate-discovery must be \"cargo-metadata\" or \"directories\""),
+    };
+
+    // crate lists given as files are used as-is
+    let use_metadata = use_metadata && !kernel_path_buf.is_file() && !apps_path_buf.is_file();
+
+    let (kernel_crates_set, mut apps_crates_set) = if use_metadata {
+        populate_crates_from_metadata(stage, config, &kernel_path_buf, &apps_path_buf)
+    } else {
+        let kernel_crates_set = match kernel_path_buf.is_file() {
+            true => populate_crates_from_file(kernel_path_buf),
+            _    => populate_crates_from_dir(kernel_path_buf),
+        }.unwrap_or_else(|e| oops!(stage, "couldn't access {}: {}", &kernel_path, e));
+
+        let apps_crates_set = match apps_path_buf.is_file() {
+            true => populate_crates_from_file(apps_path_buf),
+            _    => populate_crates_from_dir(apps_path_buf),
+        }.unwrap_or_else(|e| oops!(stage, "couldn't access {}: {}", &apps_path, e));
+
+        (kernel_crates_set, apps_crates_set)
+    };
+
+    apps_crates_set.extend(extra_apps);
+
+    (kernel_crates_set, apps_crates_set)
+}
+
+/// Classifies the workspace members reported by `cargo metadata`
+/// according to the location of their manifest.
+///
+/// Returns the sets of kernel and application crate names,
+/// as they appear in artifact file names (e.g. with underscores instead of hyphens).
+fn populate_crates_from_metadata(
+    stage: &str,
+    config: &Config,
+    kernel_dir: &Path,
+    apps_dir: &Path,
+) -> (HashSet<String>, HashSet<String>) {
+    let mut kernel_crates = HashSet::new();
+    let mut app_crates = HashSet::new();
+
+    for package in cargo_metadata(stage, config).packages {
+        let manifest_path = canonicalize(&package.manifest_path).unwrap_or(package.manifest_path.clone());
+        if manifest_path.starts_with(kernel_dir) {
+            kernel_crates.insert(package.crate_name());
+        } else if manifest_path.starts_with(apps_dir) {
+            app_crates.insert(package.crate_name());
+        }
+    }
+
+    (kernel_crates, app_crates)
+}
+
+/// Applies the `include-*` and `exclude-*` glob patterns to the given kernel and application crates.
+///
+/// Selected crates also pull in the crates they depend on, even if those were excluded,
+/// as well as `discover-graph.root`. `extra-apps`, which aren't in the crate graph, are always kept.
+///
+/// Returns `None` if no crates are filtered out, otherwise the selected kernel and application crates.
+fn select_crates(
+    stage: &str,
+    config: &Config,
+    kernel_crates: &HashSet<String>,
+    app_crates: &HashSet<String>,
+) -> Option<(HashSet<String>, HashSet<String>)> {
+    let include_kernel = config.vec("copy-crate-objects.include-kernel-crates");
+    let exclude_kernel = config.vec("copy-crate-objects.exclude-kernel-crates");
+    let include_apps = config.vec("copy-crate-objects.include-apps");
+    let exclude_apps = config.vec("copy-crate-objects.exclude-apps");
+    let extra_apps = config.vec("copy-crate-objects.extra-apps");
+    let root = config.str("discover-graph.root");
+
+    let everything = |include: &[String], exclude: &[String]| include == ["*"] && exclude.is_empty();
+    if everything(&include_kernel, &exclude_kernel) && everything(&include_apps, &exclude_apps) {
+        return None;
+    }
+
+    let select = |crates: &HashSet<String>, include: &[String], exclude: &[String]| {
+        crates.iter()
+            .filter(|name| include.iter().any(|pattern| glob_match(pattern, name)))
+            .filter(|name| !exclude.iter().any(|pattern| glob_match(pattern, name)))
+            .cloned()
+            .collect::<HashSet<_>>()
+    };
+
+    let mut selected_kernel = select(kernel_crates, &include_kernel, &exclude_kernel);
+    let mut selected_apps = select(app_crates, &include_apps, &exclude_apps);
+    selected_apps.extend(extra_apps);
+
+    log!(stage, "computing the dependencies of selected crates");
+
+    let graph = CrateGraph::load(stage, config);
+
+    // crates are selected by the names used for object files,
+    // which may either be directory names or crate names
+    let node_of = |name: &String| graph.crates.values().find(|info| {
+        &info.name == name || &info.package.replace('-', "_") == name
+    }).map(|info| info.package.clone());
+    let name_of = |package: &String| {
+        let info = &graph.crates[package];
+        let crate_name = info.package.replace('-', "_");
+        [ info.name.clone(), crate_name ].into_iter()
+            .find(|name| kernel_crates.contains(name) || app_crates.contains(name))
+    };
+
+    let mut roots = selected_kernel.iter().chain(selected_apps.iter())
+        .filter_map(node_of)
+        .collect::<Vec<_>>();
+    if graph.crates.contains_key(&root) {
+        roots.push(root);
+    }
+
+    for package in graph.dependencies(roots.clone()) {
+        let name = match name_of(&package) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let (selected, exclude) = match kernel_crates.contains(&name) {
+            true => (&mut selected_kernel, &exclude_kernel),
+            _    => (&mut selected_apps, &exclude_apps),
+        };
+
+        // dependencies which simply weren't included are expected, only explicit exclusions are overridden
+        if selected.insert(name.clone()) && exclude.iter().any(|pattern| glob_match(pattern, &name)) {
+            let path = roots.iter().find_map(|root| graph.path(root, &package)).unwrap_or_default();
+            log!(stage, "warning: {} is excluded but required ({}); including it anyway", name, path.join(" -> "));
+        }
+    }
+
+    Some((selected_kernel, selected_apps))
+}
+
+/// Matches a crate name against a glob pattern, in which
+/// `*` matches any sequence of characters and `?` matches any character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    // classic backtracking on the last `*`
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 /// Parses the file as a list of crate names, one per line.
 /// 
 /// Returns the set of unique crate names. 
@@ -202,11 +413,11 @@ fn populate_crates_from_dir<P: AsRef<Path>>(dir_path: P) -> Result<HashSet<Strin
 
 /// A key-value set of crate dependency files, in which 
 /// the key is the crate name, and the value is the crate's object file.
-type CrateObjectFiles = HashMap<String, DirEntry>;
+type CrateObjectFiles = HashMap<String, PathBuf>;
 /// A key-value set of crate dependency files, in which 
 /// the key is the crate name, and 
 /// the value is a tuple of the crate's `(object file, [.rmeta file, .rlib file])`. 
-type CrateObjectAndDepsFiles = HashMap<String, (DirEntry, [PathBuf; 2])>;
+type CrateObjectAndDepsFiles = HashMap<String, (PathBuf, [PathBuf; 2])>;
 
 
 const DEPS_PREFIX:     &str = "lib";
@@ -227,10 +438,9 @@ const RLIB_EXTENSION:  &str = "rlib";
 /// * all other non-application dependency files (.rmeta and .rlib).
 /// 
 fn parse_input_dir(
-    app_crates: HashSet<String>,
-    kernel_crates: HashSet<String>,
-    input_dirs: Vec<String>,
-    debug_crates_objects: bool,
+    app_crates: &HashSet<String>,
+    kernel_crates: &HashSet<String>,
+    input_dirs: &[String],
 ) -> IoResult<(
     CrateObjectFiles,
     CrateObjectAndDepsFiles,
@@ -241,7 +451,7 @@ fn parse_input_dir(
     let mut kernel_files = CrateObjectAndDepsFiles::new();
     let mut other_files = CrateObjectAndDepsFiles::new();
 
-    for input_dir in &input_dirs {
+    for input_dir in input_dirs {
         for dir_entry in read_dir(input_dir)? {
             let dir_entry = dir_entry?;
             let metadata = dir_entry.metadata()?;
@@ -253,8 +463,8 @@ fn parse_input_dir(
             let modified_time = metadata.modified()?;
 
             // A closure for calculating paths for .rmeta and .rlib files in the same directory as the given object file.
-            let generate_deps_paths = |obj_file: DirEntry| {
-                let mut rmeta_path = obj_file.path();
+            let generate_deps_paths = |obj_file: PathBuf| {
+                let mut rmeta_path = obj_file.clone();
                 rmeta_path.set_file_name(format!("{}{}.{}", DEPS_PREFIX, file_stem, RMETA_EXTENSION));
                 let mut rlib_path = rmeta_path.clone();
                 rlib_path.set_extension(RLIB_EXTENSION);
@@ -266,41 +476,31 @@ fn parse_input_dir(
                 match app_objects.entry(prefix.to_string()) {
                     Entry::Occupied(mut occupied) => {
                         if occupied.get().metadata()?.modified()? < modified_time {
-                            occupied.insert(dir_entry);
+                            occupied.insert(dir_entry.path());
                         }
                     }
                     Entry::Vacant(vacant) => {
-                        vacant.insert(dir_entry);
+                        vacant.insert(dir_entry.path());
                     }
                 }
             } else if kernel_crates.contains(prefix) {
                 match kernel_files.entry(prefix.to_string()) {
                     Entry::Occupied(mut occupied) => {
                         if occupied.get().0.metadata()?.modified()? < modified_time {
-                            occupied.insert(generate_deps_paths(dir_entry));
+                            occupied.insert(generate_deps_paths(dir_entry.path()));
                         }
                     }
                     Entry::Vacant(vacant) => {
-                        vacant.insert(generate_deps_paths(dir_entry));
+                        vacant.insert(generate_deps_paths(dir_entry.path()));
                     }
                 }
             } else {
-                other_files.insert(file_stem.to_string(), generate_deps_paths(dir_entry));
+                other_files.insert(file_stem.to_string(), generate_deps_paths(dir_entry.path()));
             }
 
         }
     }
 
-    // optional debug output
-    if debug_crates_objects {
-        println!("APPLICATION OBJECT FILES:");
-        print_crates_objects(&app_objects, PRINT_SORTED);
-        println!("KERNEL OBJECT FILES AND DEPS FILES:");
-        print_crates_objects_and_deps(&kernel_files, PRINT_SORTED);
-        println!("OTHER OBJECT FILES AND DEPS FILES:");
-        print_crates_objects_and_deps(&other_files, PRINT_SORTED);
-    }
-    
     Ok((
         app_objects,
         kernel_files,
@@ -308,10 +508,38 @@ fn parse_input_dir(
     ))
 }
 
+/// Sorts the artifacts recorded by `build-cells` into application, kernel and other crates,
+/// the same way `parse_input_dir` does, except that no guessing is involved.
+///
+/// Artifacts replace any file previously found for the same crate.
+fn parse_artifacts(
+    app_crates: &HashSet<String>,
+    kernel_crates: &HashSet<String>,
+    artifacts: Vec<Artifact>,
+    found: &mut (CrateObjectFiles, CrateObjectAndDepsFiles, CrateObjectAndDepsFiles),
+) {
+    let (app_objects, kernel_files, other_files) = found;
+
+    for artifact in artifacts {
+        let Artifact { crate_name, object, rmeta, rlib, .. } = artifact;
+
+        if app_crates.contains(&crate_name) {
+            app_objects.insert(crate_name, object);
+        } else if kernel_crates.contains(&crate_name) {
+            kernel_files.insert(crate_name, (object, [rmeta, rlib]));
+        } else {
+            // other crates are keyed by their file stem, e.g. `core-0123456789abcdef`
+            let file_stem = object.file_stem().unwrap().to_string_lossy().to_string();
+            other_files.insert(file_stem, (object, [rmeta, rlib]));
+        }
+    }
+}
+
 
 /// Copies each file in the `files` iterator into the given `output_dir`.
 ///
-/// Prepends the given `prefix` onto the front of the output file names.
+/// Prepends the given `prefix` onto the front of the output file names,
+/// which are returned.
 /// 
 /// Ignores any source files in the `files` iterator that do not exist. 
 /// This is a policy choice due to how we form paths for deps files, which may not actually exist. 
@@ -320,27 +548,29 @@ fn copy_files<'p, O, P, I>(
     files: I,
     prefix: &str,
     debug_crates_objects: bool,
-) -> IoResult<()> 
+) -> IoResult<Vec<String>> 
     where O: AsRef<Path>,
           P: AsRef<Path>,
           I: Iterator<Item = P>,
 {
+    let mut copied = Vec::new();
     for source_path_ref in files {
         let source_path = source_path_ref.as_ref();
         let mut dest_path = output_dir.as_ref().to_path_buf();
-        dest_path.push(format!("{}{}", prefix, source_path.file_name().and_then(|osstr| osstr.to_str()).unwrap()));
+        let dest_name = format!("{}{}", prefix, source_path.file_name().and_then(|osstr| osstr.to_str()).unwrap());
+        dest_path.push(&dest_name);
 
         if debug_crates_objects {
             println!("Copying {} to {}", source_path.display(), dest_path.display());
         }
             
         match copy(source_path, dest_path) {
-            Ok(_bytes_copied) => { }
+            Ok(_bytes_copied) => copied.push(dest_name),
             Err(e) if e.kind() == ErrorKind::NotFound => { }  // Ignore source files that don't exist
             Err(other_err) => return Err(other_err),
         }
     }
-    Ok(())
+    Ok(copied)
 }
 
 
@@ -354,7 +584,7 @@ fn print_crates_objects(objects: &CrateObjectFiles, sorted: bool) {
         }
     } else {
         for (k, v) in objects.iter() {
-            println!("\t{} --> {}", k, v.path().display());
+            println!("\t{} --> {}", k, v.display());
         }
     }
 }
@@ -369,7 +599,7 @@ fn print_crates_objects_and_deps(files: &CrateObjectAndDepsFiles, sorted: bool)
         }
     } else {
         for (k, v) in files.iter() {
-            println!("\t{} --> {}, {}, {}", k, v.0.path().display(), v.1[0].display(), v.1[1].display());
+            println!("\t{} --> {}, {}, {}", k, v.0.display(), v.1[0].display(), v.1[1].display());
         }
     }
 }
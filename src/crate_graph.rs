@@ -0,0 +1,227 @@
+use crate::log;
+use crate::oops;
+use crate::Config;
+use crate::copy_crate_objects::classify_crates;
+use crate::discover::discover_crates;
+use crate::discover::set_kinds;
+use crate::discover::CrateInfo;
+
+use std::fs::write;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+
+use serde_json::json;
+
+/// The dependency graph of all crates in `directories.kernel` and `directories.apps`.
+///
+/// Crates are identified by their package name.
+pub struct CrateGraph {
+    pub crates: BTreeMap<String, CrateInfo>,
+}
+
+/// Answers `discover-graph.*` queries, if any was requested.
+pub fn process(config: &Config) {
+    let stage = "discover";
+
+    let export = config.str("discover-graph.export");
+    let output = config.str("discover-graph.output");
+    let root = config.str("discover-graph.root");
+    let reverse_deps = config.str("discover-graph.reverse-deps");
+    let path = config.vec("discover-graph.path");
+    let unreachable = config.bool("discover-graph.unreachable");
+
+    let nothing_to_do = export == "none"
+        && reverse_deps.is_empty()
+        && path.is_empty()
+        && !unreachable;
+
+    if nothing_to_do {
+        return;
+    }
+
+    log!(stage, "building the crate dependency graph");
+
+    let graph = CrateGraph::load(stage, config);
+
+    let exported = match export.as_str() {
+        "none" => None,
+        "dot" => Some(graph.to_dot()),
+        "json" => Some(graph.to_json()),
+        _ => oops!(stage, "discover-graph.export must be \"none\", \"dot\" or \"json\""),
+    };
+
+    if let Some(exported) = exported {
+        if output.is_empty() {
+            println!("{}", exported);
+        } else {
+            log!(stage, "writing the crate graph to {}", output);
+            write(&output, &exported).unwrap();
+        }
+    }
+
+    if !reverse_deps.is_empty() {
+        graph.check(stage, &reverse_deps);
+        let direct = graph.direct_dependents(&reverse_deps);
+        println!("crates depending on {}:", reverse_deps);
+        for name in graph.dependents(&reverse_deps) {
+            let kind = graph.crates[&name].kind;
+            let how = if direct.contains(&name) { "directly" } else { "indirectly" };
+            println!("• {:30} {:12} {}", name, kind, how);
+        }
+        println!();
+    }
+
+    if !path.is_empty() {
+        let (from, to) = match path.as_slice() {
+            [from, to] => (from, to),
+            _ => oops!(stage, "discover-graph.path must contain exactly two crate names"),
+        };
+        graph.check(stage, from);
+        graph.check(stage, to);
+        match graph.path(from, to) {
+            Some(path) => println!("{}", path.join(" -> ")),
+            None => println!("{} does not depend on {}", from, to),
+        }
+        println!();
+    }
+
+    if unreachable {
+        graph.check(stage, &root);
+        let reachable = graph.dependencies([root.clone()]);
+        println!("crates unreachable from {}:", root);
+        for (name, info) in &graph.crates {
+            if !reachable.contains(name) {
+                println!("• {:30} {}", name, info.kind);
+            }
+        }
+        println!();
+    }
+}
+
+impl CrateGraph {
+    pub fn load(stage: &str, config: &Config) -> Self {
+        let kernel_dir = config.str("directories.kernel");
+        let apps_dir = config.str("directories.apps");
+
+        let (kernel_crates, app_crates) = classify_crates(stage, config);
+
+        let mut crates = BTreeMap::new();
+        for dir in [ &kernel_dir, &apps_dir ] {
+            let mut discovered = discover_crates(stage, dir);
+            set_kinds(&mut discovered, &kernel_crates, &app_crates);
+            for info in discovered {
+                crates.insert(info.package.clone(), info);
+            }
+        }
+
+        // path dependencies outside of these directories aren't part of the graph
+        let known = crates.keys().cloned().collect::<BTreeSet<_>>();
+        for info in crates.values_mut() {
+            info.dependencies.retain(|dep| known.contains(dep));
+        }
+
+        Self { crates }
+    }
+
+    fn check(&self, stage: &str, name: &str) {
+        if !self.crates.contains_key(name) {
+            oops!(stage, "unknown crate {}", name);
+        }
+    }
+
+    /// Returns the names of the crates which `name` depends on directly.
+    fn direct_dependencies(&self, name: &str) -> &[String] {
+        self.crates.get(name).map(|info| info.dependencies.as_slice()).unwrap_or(&[])
+    }
+
+    fn direct_dependents(&self, name: &str) -> BTreeSet<String> {
+        self.crates.values()
+            .filter(|info| info.dependencies.iter().any(|dep| dep == name))
+            .map(|info| info.package.clone())
+            .collect()
+    }
+
+    /// Computes the transitive closure of the dependencies of `roots`, roots included.
+    pub fn dependencies<I: IntoIterator<Item = String>>(&self, roots: I) -> BTreeSet<String> {
+        let mut visited = BTreeSet::new();
+        let mut queue = roots.into_iter().collect::<VecDeque<_>>();
+        while let Some(name) = queue.pop_front() {
+            if visited.insert(name.clone()) {
+                queue.extend(self.direct_dependencies(&name).iter().cloned());
+            }
+        }
+        visited
+    }
+
+    /// Returns every crate which depends on `name`, directly or not.
+    pub fn dependents(&self, name: &str) -> BTreeSet<String> {
+        let mut visited = BTreeSet::new();
+        let mut queue = VecDeque::from([ name.to_string() ]);
+        while let Some(name) = queue.pop_front() {
+            for dependent in self.direct_dependents(&name) {
+                if visited.insert(dependent.clone()) {
+                    queue.push_back(dependent);
+                }
+            }
+        }
+        visited
+    }
+
+    /// Finds one of the shortest dependency chains from `from` to `to`.
+    pub fn path(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        let mut parents = BTreeMap::new();
+        let mut queue = VecDeque::from([ from.to_string() ]);
+        parents.insert(from.to_string(), None);
+
+        while let Some(name) = queue.pop_front() {
+            if name == to {
+                let mut path = vec![ name ];
+                while let Some(Some(parent)) = parents.get(path.last().unwrap()) {
+                    path.push(String::clone(parent));
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            for dep in self.direct_dependencies(&name) {
+                if !parents.contains_key(dep) {
+                    parents.insert(dep.clone(), Some(name.clone()));
+                    queue.push_back(dep.clone());
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph theseus {\n");
+        for (name, info) in &self.crates {
+            let shape = match info.kind {
+                "kernel" => "box",
+                "application" => "ellipse",
+                _ => "diamond",
+            };
+            dot.push_str(&format!("\t\"{}\" [shape={}];\n", name, shape));
+        }
+        for (name, info) in &self.crates {
+            for dep in &info.dependencies {
+                dot.push_str(&format!("\t\"{}\" -> \"{}\";\n", name, dep));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        let nodes = self.crates.values()
+            .map(|info| json!({ "name": info.package, "kind": info.kind }))
+            .collect::<Vec<_>>();
+        let edges = self.crates.values()
+            .flat_map(|info| info.dependencies.iter().map(move |dep| json!([ info.package, dep ])))
+            .collect::<Vec<_>>();
+        let graph = json!({ "nodes": nodes, "edges": edges });
+        serde_json::to_string_pretty(&graph).unwrap()
+    }
+}
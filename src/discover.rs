@@ -1,46 +1,188 @@
 use std::fs::read_to_string;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
 
 use crate::log;
 use crate::oops;
 use crate::Config;
 use crate::list_dir;
+use crate::copy_crate_objects::classify_crates;
+use crate::crate_graph;
 
+use serde::Serialize;
 use toml::Value;
 
+/// What `discover` knows about a crate, from its manifest.
+#[derive(Serialize)]
+pub struct CrateInfo {
+    /// The name of the crate's directory
+    pub name: String,
+    pub package: String,
+    pub version: Option<String>,
+    pub description: String,
+    /// "kernel", "application" or "other", as `copy-crate-objects` sees it
+    pub kind: &'static str,
+    /// Path dependencies, i.e., dependencies on other Theseus crates
+    pub dependencies: Vec<String>,
+    pub features: Vec<String>,
+}
+
 pub fn process(config: &Config) {
     let stage = "discover";
 
     let root = config.str("theseus-root");
     let discover = config.vec("discover");
+    let details = config.bool("discover-details");
+    let format = config.str("discover-format");
+
+    let json = match format.as_str() {
+        "text" => false,
+        "json" => true,
+        _ => oops!(stage, "discover-format must be \"text\" or \"json\""),
+    };
+
+    let (kernel_crates, app_crates) = match details || json {
+        true => classify_crates(stage, config),
+        _    => Default::default(),
+    };
+
+    let mut discovered = BTreeMap::new();
 
     for subdir in &discover {
         log!(stage, "discovering {}", subdir);
 
         let dir = format!("{}/{}", &root, subdir);
-        for (name, is_dir) in list_dir(stage, &dir) {
-            if is_dir {
-                let manifest = format!("{}/{}/Cargo.toml", &dir, &name);
-                let manifest = match read_to_string(&manifest) {
-                    Ok(manifest) => manifest,
-                    Err(e) => oops!(stage, "failed to read {}'s manifest: {}", name, e),
-                };
-                let manifest = match manifest.parse::<Value>() {
-                    Ok(value) => value,
-                    Err(e) => oops!(stage, "failed to parse {}'s manifest: {}", name, e),
-                };
-
-                let mut description = "";
-                if let Some(package) = manifest.get("package") {
-                    if let Some(value) = package.get("description") {
-                        if let Some(slice) = value.as_str() {
-                            description = slice;
-                        }
-                    }
+        let mut crates = discover_crates(stage, &dir);
+        set_kinds(&mut crates, &kernel_crates, &app_crates);
+
+        if json {
+            discovered.insert(subdir.clone(), crates);
+            continue;
+        }
+
+        for info in &crates {
+            println!("• {:30} {}", info.name, info.description);
+
+            if details {
+                let version = info.version.as_deref().unwrap_or("?");
+                println!("  {:30} version {}, {}", "", version, info.kind);
+                if !info.features.is_empty() {
+                    println!("  {:30} features: {}", "", info.features.join(", "));
+                }
+                if !info.dependencies.is_empty() {
+                    println!("  {:30} depends on: {}", "", info.dependencies.join(", "));
                 }
-                println!("• {:30} {}", name, description);
             }
         }
 
         println!("");
     }
-}
\ No newline at end of file
+
+    if json {
+        match serde_json::to_string_pretty(&discovered) {
+            Ok(string) => println!("{}", string),
+            Err(e) => oops!(stage, "failed to serialize crate list: {}", e),
+        }
+    }
+
+    crate_graph::process(config);
+}
+
+/// Classifies crates the same way `copy-crate-objects` does.
+pub fn set_kinds(crates: &mut [CrateInfo], kernel_crates: &HashSet<String>, app_crates: &HashSet<String>) {
+    for info in crates {
+        // depending on `copy-crate-objects.crate-discovery`,
+        // the sets contain either directory names or crate names
+        let crate_name = info.package.replace('-', "_");
+        let is = |set: &HashSet<String>| set.contains(&info.name) || set.contains(&crate_name);
+
+        info.kind = if is(kernel_crates) {
+            "kernel"
+        } else if is(app_crates) {
+            "application"
+        } else {
+            "other"
+        };
+    }
+}
+
+/// Reads the manifest of each crate directly inside `dir`.
+///
+/// Directories without a `Cargo.toml` or without a `[package]` table are skipped.
+/// The `kind` of each returned crate is left to "other".
+pub fn discover_crates(stage: &str, dir: &str) -> Vec<CrateInfo> {
+    let mut crates = Vec::new();
+
+    let mut entries = list_dir(stage, dir);
+    entries.sort();
+
+    for (name, is_dir) in entries {
+        if !is_dir {
+            continue;
+        }
+
+        let manifest = format!("{}/{}/Cargo.toml", dir, &name);
+        let manifest = match read_to_string(&manifest) {
+            Ok(manifest) => manifest,
+            Err(_) => {
+                log!(stage, "skipping {}: no manifest", name);
+                continue;
+            },
+        };
+        let manifest = match manifest.parse::<Value>() {
+            Ok(value) => value,
+            Err(e) => oops!(stage, "failed to parse {}'s manifest: {}", name, e),
+        };
+
+        let package = match manifest.get("package") {
+            Some(package) => package,
+            None => {
+                log!(stage, "skipping {}: not a package", name);
+                continue;
+            },
+        };
+
+        let get_str = |key| package.get(key).and_then(Value::as_str).map(String::from);
+
+        let mut dependencies = path_dependencies(manifest.get("dependencies"));
+        if let Some(Value::Table(targets)) = manifest.get("target") {
+            for target in targets.values() {
+                dependencies.extend(path_dependencies(target.get("dependencies")));
+            }
+        }
+        dependencies.sort();
+        dependencies.dedup();
+
+        let features = match manifest.get("features") {
+            Some(Value::Table(features)) => features.keys().cloned().collect(),
+            _ => Vec::new(),
+        };
+
+        crates.push(CrateInfo {
+            package: get_str("name").unwrap_or_else(|| name.clone()),
+            version: get_str("version"),
+            description: get_str("description").unwrap_or_default(),
+            kind: "other",
+            dependencies,
+            features,
+            name,
+        });
+    }
+
+    crates
+}
+
+/// Lists the dependencies of a `[dependencies]` table which are specified by path.
+fn path_dependencies(table: Option<&Value>) -> Vec<String> {
+    let mut dependencies = Vec::new();
+    if let Some(Value::Table(table)) = table {
+        for (key, value) in table {
+            if value.get("path").is_some() {
+                // dependencies can be renamed using `package = "..."`
+                let name = value.get("package").and_then(Value::as_str).unwrap_or(key);
+                dependencies.push(name.to_string());
+            }
+        }
+    }
+    dependencies
+}
@@ -0,0 +1,279 @@
+//! Writes raw hard disk images: a partition table (GPT or MBR) and
+//! a single EFI system partition, formatted as FAT32.
+//!
+//! Identifiers (GUIDs, disk signature, volume serial number) are derived
+//! from the options, so that images are reproducible.
+
+use crate::fat32;
+
+use std::fs::File;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+const SECTOR: u64 = 512;
+/// Partitions start at 1 MiB, leaving room for the partition table and the bootloader.
+const PARTITION_START: u64 = 2048;
+const GPT_ENTRIES: u64 = 128;
+const GPT_ENTRY_SIZE: u64 = 128;
+/// Header and partition entry array
+const GPT_SECTORS: u64 = 1 + GPT_ENTRIES * GPT_ENTRY_SIZE / SECTOR;
+
+/// C12A7328-F81F-11D2-BA4B-00A0C93EC93B, in its on-disk encoding
+const ESP_TYPE_GUID: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11,
+    0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+const MBR_ESP_TYPE: u8 = 0xef;
+const MBR_GPT_PROTECTIVE_TYPE: u8 = 0xee;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PartitionTable {
+    Gpt,
+    Mbr,
+}
+
+pub struct Options {
+    pub partition_table: PartitionTable,
+    /// Size of the EFI system partition in MiB, or 0 for as small as possible
+    pub esp_size: u64,
+    pub label: String,
+    /// Seconds since 1970, for all timestamps
+    pub mtime: u64,
+}
+
+/// Writes an image to `output`, with `files` (paths in the partition mapped to source files)
+/// in its EFI system partition.
+pub fn write_disk_image(output: &Path, files: &[(String, PathBuf)], options: &Options) -> Result<(), String> {
+    let io = |e: io::Error| format!("couldn't write {}: {}", output.display(), e);
+
+    let mut sizes = Vec::new();
+    for (_, source) in files {
+        let metadata = source.metadata().map_err(|e| format!("couldn't read {}: {}", source.display(), e))?;
+        sizes.push(metadata.len());
+    }
+
+    let minimum = fat32::minimum_sectors(&sizes);
+    let esp_sectors = match options.esp_size {
+        0 => align_up(minimum, PARTITION_START),
+        size if size * 2048 >= minimum => size * 2048,
+        size => return Err(format!("an EFI system partition of {} MiB is too small; it needs {} MiB", size, align_up(minimum, 2048) / 2048)),
+    };
+
+    // the backup GPT lives in the last sectors
+    let total_sectors = PARTITION_START + esp_sectors + match options.partition_table {
+        PartitionTable::Gpt => PARTITION_START,
+        PartitionTable::Mbr => 0,
+    };
+    if options.partition_table == PartitionTable::Mbr && total_sectors > u32::MAX as u64 {
+        return Err("the image is too large for an MBR partition table".into());
+    }
+
+    let seed = format!("{}:{}", options.label, options.mtime);
+    let mut file = File::create(output).map_err(io)?;
+    file.set_len(total_sectors * SECTOR).map_err(io)?;
+
+    match options.partition_table {
+        PartitionTable::Gpt => {
+            write_at(&mut file, 0, &mbr(&[ (MBR_GPT_PROTECTIVE_TYPE, 1, total_sectors - 1, false) ], &seed)).map_err(io)?;
+            write_gpt(&mut file, total_sectors, esp_sectors, &seed).map_err(io)?;
+        },
+        PartitionTable::Mbr => {
+            write_at(&mut file, 0, &mbr(&[ (MBR_ESP_TYPE, PARTITION_START, esp_sectors, true) ], &seed)).map_err(io)?;
+        },
+    }
+
+    let fat_options = fat32::Options {
+        label: options.label.clone(),
+        volume_id: u32::from_le_bytes(derive_id(&seed, "volume")[..4].try_into().unwrap()),
+        mtime: options.mtime,
+    };
+    fat32::write_fat32(&mut file, PARTITION_START * SECTOR, esp_sectors, files, &fat_options)
+}
+
+/// A master boot record with the given partitions: (type, first sector, sectors, bootable).
+fn mbr(partitions: &[(u8, u64, u64, bool)], seed: &str) -> Vec<u8> {
+    let mut sector = vec![0; SECTOR as usize];
+    sector[440..444].copy_from_slice(&derive_id(seed, "disk signature")[..4]);
+
+    for (i, (kind, start, sectors, bootable)) in partitions.iter().enumerate() {
+        let entry = &mut sector[446 + i * 16..][..16];
+        entry[0] = match bootable {
+            true => 0x80,
+            false => 0,
+        };
+        // CHS addresses are unused; these are the values meaning "use LBA"
+        entry[1..4].copy_from_slice(&[ 0xfe, 0xff, 0xff ]);
+        entry[4] = *kind;
+        entry[5..8].copy_from_slice(&[ 0xfe, 0xff, 0xff ]);
+        entry[8..12].copy_from_slice(&(*start as u32).to_le_bytes());
+        entry[12..16].copy_from_slice(&((*sectors).min(u32::MAX as u64) as u32).to_le_bytes());
+    }
+
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+    sector
+}
+
+/// Writes the primary and backup GPT headers and partition entry arrays.
+fn write_gpt(file: &mut File, total_sectors: u64, esp_sectors: u64, seed: &str) -> io::Result<()> {
+    let mut entries = vec![0; (GPT_ENTRIES * GPT_ENTRY_SIZE) as usize];
+    entries[0..16].copy_from_slice(&ESP_TYPE_GUID);
+    entries[16..32].copy_from_slice(&derive_guid(seed, "partition"));
+    entries[32..40].copy_from_slice(&PARTITION_START.to_le_bytes());
+    entries[40..48].copy_from_slice(&(PARTITION_START + esp_sectors - 1).to_le_bytes());
+    let name = "EFI System Partition".encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect::<Vec<_>>();
+    entries[56..56 + name.len()].copy_from_slice(&name);
+
+    let entries_crc = crc32fast::hash(&entries);
+    let disk_guid = derive_guid(seed, "disk");
+    let last_lba = total_sectors - 1;
+
+    let header = |lba: u64, alternate_lba: u64, entries_lba: u64| {
+        let mut header = Vec::new();
+        header.extend_from_slice(b"EFI PART");
+        header.extend_from_slice(&0x00010000u32.to_le_bytes());
+        header.extend_from_slice(&92u32.to_le_bytes());
+        // checksum, patched below, and reserved
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&lba.to_le_bytes());
+        header.extend_from_slice(&alternate_lba.to_le_bytes());
+        // usable sectors
+        header.extend_from_slice(&(1 + GPT_SECTORS).to_le_bytes());
+        header.extend_from_slice(&(last_lba - GPT_SECTORS).to_le_bytes());
+        header.extend_from_slice(&disk_guid);
+        header.extend_from_slice(&entries_lba.to_le_bytes());
+        header.extend_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+        header.extend_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&entries_crc.to_le_bytes());
+
+        let crc = crc32fast::hash(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    };
+
+    let backup_entries_lba = last_lba - (GPT_SECTORS - 1);
+    write_at(file, SECTOR, &header(1, last_lba, 2))?;
+    write_at(file, 2 * SECTOR, &entries)?;
+    write_at(file, backup_entries_lba * SECTOR, &entries)?;
+    write_at(file, last_lba * SECTOR, &header(last_lba, 1, backup_entries_lba))
+}
+
+fn derive_id(seed: &str, purpose: &str) -> [u8; 32] {
+    Sha256::digest(format!("{}:{}", seed, purpose).as_bytes()).into()
+}
+
+/// A random-looking (version 4) GUID, derived from `seed` and `purpose`.
+fn derive_guid(seed: &str, purpose: &str) -> [u8; 16] {
+    let mut guid = [0; 16];
+    guid.copy_from_slice(&derive_id(seed, purpose)[..16]);
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    guid
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+fn write_at(file: &mut File, offset: u64, bytes: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::u32_le;
+    use crate::test_util::u64_le;
+    use crate::test_util::TempDir;
+
+    use std::fs::read;
+    use std::fs::write;
+
+    fn image(name: &str, partition_table: PartitionTable) -> Vec<u8> {
+        let dir = TempDir::new(name);
+        let kernel = dir.join("kernel");
+        write(&kernel, b"kernel").unwrap();
+
+        let options = Options { partition_table, esp_size: 0, label: "THESEUS".into(), mtime: 0 };
+        let output = dir.join("image");
+        write_disk_image(&output, &[ ("boot/kernel".into(), kernel) ], &options).unwrap();
+
+        read(&output).unwrap()
+    }
+
+    /// Checks a GPT header's CRC and its entries' CRC, and returns the header.
+    fn gpt_header(image: &[u8], lba: u64) -> &[u8] {
+        let header = &image[(lba * SECTOR) as usize..][..92];
+        assert_eq!(&header[0..8], b"EFI PART");
+        assert_eq!(u64_le(header, 24), lba);
+
+        let mut zeroed = header.to_vec();
+        zeroed[16..20].copy_from_slice(&[0; 4]);
+        assert_eq!(u32_le(header, 16), crc32fast::hash(&zeroed));
+
+        let entries = &image[(u64_le(header, 72) * SECTOR) as usize..][..(GPT_ENTRIES * GPT_ENTRY_SIZE) as usize];
+        assert_eq!(u32_le(header, 88), crc32fast::hash(entries));
+        header
+    }
+
+    #[test]
+    fn gpt() {
+        let image = image("gpt", PartitionTable::Gpt);
+        let last_lba = image.len() as u64 / SECTOR - 1;
+
+        // protective MBR covering the whole disk
+        assert_eq!(image[446 + 4], MBR_GPT_PROTECTIVE_TYPE);
+        assert_eq!(u32_le(&image, 446 + 8), 1);
+        assert_eq!(u32_le(&image, 446 + 12) as u64, last_lba);
+        assert_eq!(&image[510..512], [ 0x55, 0xaa ]);
+
+        let primary = gpt_header(&image, 1);
+        assert_eq!((u64_le(primary, 32), u64_le(primary, 72)), (last_lba, 2));
+
+        // the backup header is in the last sector, its entries just before it
+        let backup = gpt_header(&image, last_lba);
+        assert_eq!((u64_le(backup, 32), u64_le(backup, 72)), (1, last_lba - 32));
+        assert!(primary[40..56] == backup[40..56] && primary[56..72] == backup[56..72]);
+        assert!(primary[80..92] == backup[80..92]);
+
+        // usable sectors exclude both tables, and hold the partition
+        let entry = &image[2 * SECTOR as usize..][..GPT_ENTRY_SIZE as usize];
+        assert_eq!(&entry[0..16], ESP_TYPE_GUID);
+        let (first, last) = (u64_le(entry, 32), u64_le(entry, 40));
+        assert_eq!(first, PARTITION_START);
+        assert!(u64_le(primary, 40) <= first && last <= u64_le(primary, 48));
+        assert!(u64_le(primary, 48) < last_lba - 32);
+
+        // the FAT32 file system fills the partition
+        let boot_sector = &image[(first * SECTOR) as usize..][..512];
+        assert_eq!(&boot_sector[82..90], b"FAT32   ");
+        assert_eq!(u32_le(boot_sector, 32) as u64, last - first + 1);
+        assert_eq!(u32_le(boot_sector, 28) as u64, first);
+    }
+
+    #[test]
+    fn mbr() {
+        let image = image("mbr", PartitionTable::Mbr);
+
+        let entry = &image[446..462];
+        assert_eq!((entry[0], entry[4]), (0x80, MBR_ESP_TYPE));
+        assert_eq!(u32_le(entry, 8) as u64, PARTITION_START);
+        assert_eq!((u32_le(entry, 8) + u32_le(entry, 12)) as u64, image.len() as u64 / SECTOR);
+        assert!(image[462..510].iter().all(|byte| *byte == 0));
+        assert_eq!(&image[510..512], [ 0x55, 0xaa ]);
+        assert_eq!(&image[(PARTITION_START * SECTOR) as usize + 82..][..8], b"FAT32   ");
+    }
+
+    #[test]
+    fn reproducible() {
+        assert!(image("same-a", PartitionTable::Gpt) == image("same-b", PartitionTable::Gpt));
+    }
+}
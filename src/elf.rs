@@ -0,0 +1,116 @@
+//! Minimal ELF64 section and program header reader, for stages which only
+//! need the layout of a file, not its contents.
+
+pub const SHT_NOBITS: u32 = 8;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+pub const SHF_EXECINSTR: u64 = 0x4;
+
+pub struct Section {
+    pub name: String,
+    pub ty: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub size: u64,
+}
+
+pub struct Segment {
+    pub ty: u32,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub memsz: u64,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset.checked_add(2)?)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset.checked_add(4)?)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset.checked_add(8)?)?.try_into().ok()?))
+}
+
+/// Returns the `index`th entry of a header table, if it's entirely in `bytes`.
+fn header_at(bytes: &[u8], table: usize, index: usize, entry_size: usize) -> Option<&[u8]> {
+    let start = index.checked_mul(entry_size)?.checked_add(table)?;
+    bytes.get(start..start.checked_add(entry_size)?)
+}
+
+fn check_header(bytes: &[u8]) -> Result<(), &'static str> {
+    if bytes.get(0..4) != Some(b"\x7fELF") {
+        return Err("not an ELF file");
+    }
+    if bytes.get(4) != Some(&2) || bytes.get(5) != Some(&1) {
+        return Err("not a little-endian ELF64 file");
+    }
+    Ok(())
+}
+
+/// Parses the section headers of a little-endian ELF64 file.
+pub fn sections(bytes: &[u8]) -> Result<Vec<Section>, &'static str> {
+    check_header(bytes)?;
+
+    let truncated = "truncated ELF file";
+
+    let shoff = u64_at(bytes, 0x28).ok_or(truncated)? as usize;
+    let shentsize = u16_at(bytes, 0x3a).ok_or(truncated)? as usize;
+    let shnum = u16_at(bytes, 0x3c).ok_or(truncated)? as usize;
+    let shstrndx = u16_at(bytes, 0x3e).ok_or(truncated)? as usize;
+
+    let header = |index: usize| header_at(bytes, shoff, index, shentsize);
+
+    // section names are in the section whose index is in the file header
+    let names_offset = match shstrndx < shnum {
+        true => Some(header(shstrndx).and_then(|header| u64_at(header, 0x18)).ok_or(truncated)? as usize),
+        false => None,
+    };
+    let name = |offset: u32| -> Option<String> {
+        let start = names_offset?.checked_add(offset as usize)?;
+        let len = bytes.get(start..)?.iter().position(|byte| *byte == 0)?;
+        Some(String::from_utf8_lossy(&bytes[start..start + len]).into_owned())
+    };
+
+    let mut sections = Vec::with_capacity(shnum);
+    for index in 0..shnum {
+        let header = header(index).ok_or(truncated)?;
+        sections.push(Section {
+            name: name(u32_at(header, 0).ok_or(truncated)?).unwrap_or_default(),
+            ty: u32_at(header, 0x04).ok_or(truncated)?,
+            flags: u64_at(header, 0x08).ok_or(truncated)?,
+            addr: u64_at(header, 0x10).ok_or(truncated)?,
+            size: u64_at(header, 0x20).ok_or(truncated)?,
+        });
+    }
+
+    Ok(sections)
+}
+
+/// Parses the program headers of a little-endian ELF64 file.
+pub fn segments(bytes: &[u8]) -> Result<Vec<Segment>, &'static str> {
+    check_header(bytes)?;
+
+    let truncated = "truncated ELF file";
+
+    let phoff = u64_at(bytes, 0x20).ok_or(truncated)? as usize;
+    let phentsize = u16_at(bytes, 0x36).ok_or(truncated)? as usize;
+    let phnum = u16_at(bytes, 0x38).ok_or(truncated)? as usize;
+
+    let mut segments = Vec::with_capacity(phnum);
+    for index in 0..phnum {
+        let header = header_at(bytes, phoff, index, phentsize).ok_or(truncated)?;
+        segments.push(Segment {
+            ty: u32_at(header, 0).ok_or(truncated)?,
+            vaddr: u64_at(header, 0x10).ok_or(truncated)?,
+            paddr: u64_at(header, 0x18).ok_or(truncated)?,
+            memsz: u64_at(header, 0x28).ok_or(truncated)?,
+        });
+    }
+
+    Ok(segments)
+}
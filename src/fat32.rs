@@ -0,0 +1,590 @@
+//! Writes FAT32 filesystems with long file names, for EFI system partitions.
+//!
+//! Clusters are one sector long, so that small partitions can still be FAT32:
+//! it needs at least 65525 clusters, i.e. about 32 MiB.
+//! Timestamps are fixed, so that images are reproducible.
+
+use std::fs::File;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+
+const SECTOR: u64 = 512;
+const RESERVED_SECTORS: u64 = 32;
+const FATS: u64 = 2;
+const MIN_CLUSTERS: u64 = 65525;
+const END_OF_CHAIN: u32 = 0x0fffffff;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+pub struct Options {
+    /// Up to 11 characters
+    pub label: String,
+    pub volume_id: u32,
+    /// Seconds since 1970, for all timestamps
+    pub mtime: u64,
+}
+
+struct Node {
+    name: String,
+    /// Source file, or None for directories
+    source: Option<PathBuf>,
+    children: Vec<usize>,
+    parent: usize,
+    size: u64,
+    first_cluster: u32,
+}
+
+/// The smallest partition size, in sectors, which can hold the given files.
+pub fn minimum_sectors(file_sizes: &[u64]) -> u64 {
+    // data, plus room for directories
+    let data = file_sizes.iter().map(|size| size.div_ceil(SECTOR)).sum::<u64>() + 64;
+    let clusters = data.max(MIN_CLUSTERS);
+    let fat_sectors = ((clusters + 2) * 4).div_ceil(SECTOR);
+    RESERVED_SECTORS + FATS * fat_sectors + clusters
+}
+
+/// Writes a filesystem of `sectors` sectors at `offset` in `file`.
+///
+/// `files` maps paths in the filesystem, separated by `/`, to source files.
+pub fn write_fat32(
+    file: &mut File,
+    offset: u64,
+    sectors: u64,
+    files: &[(String, PathBuf)],
+    options: &Options,
+) -> Result<(), String> {
+    let io = |e: io::Error| e.to_string();
+
+    let mut nodes = vec![ Node {
+        name: String::new(),
+        source: None,
+        children: Vec::new(),
+        parent: 0,
+        size: 0,
+        first_cluster: 0,
+    } ];
+
+    for (path, source) in files {
+        let size = source.metadata().map_err(|e| format!("couldn't read {}: {}", source.display(), e))?.len();
+        add_file(&mut nodes, path, source.clone(), size)?;
+    }
+
+    // the smallest FAT size which fits the clusters left by both FATs:
+    // (sectors - reserved - 2 * fat_sectors + 2) * 4 <= fat_sectors * 512
+    let entries_bytes = (sectors.saturating_sub(RESERVED_SECTORS) + 2) * 4;
+    let fat_sectors = entries_bytes.div_ceil(SECTOR + FATS * 4);
+    let clusters = sectors.saturating_sub(RESERVED_SECTORS + FATS * fat_sectors);
+    if clusters < MIN_CLUSTERS {
+        return Err(format!("a FAT32 partition needs at least {} clusters, but only {} fit", MIN_CLUSTERS, clusters));
+    }
+
+    // directories are stored first, then files, each in a contiguous run of clusters
+    let mut fat = vec![ 0x0ffffff8, END_OF_CHAIN ];
+    let mut directories = Vec::new();
+    let mut encoded = Vec::new();
+
+    let mut order = (0..nodes.len()).filter(|index| nodes[*index].source.is_none()).collect::<Vec<_>>();
+    order.extend((0..nodes.len()).filter(|index| nodes[*index].source.is_some()));
+
+    for &index in &order {
+        if nodes[index].source.is_none() {
+            // the size of a directory doesn't depend on the clusters it refers to
+            let size = directory(&nodes, index, options)?.len() as u64;
+            nodes[index].size = size;
+        }
+
+        let node_clusters = nodes[index].size.div_ceil(SECTOR).max(nodes[index].source.is_none() as u64);
+        if node_clusters == 0 {
+            continue;
+        }
+
+        let first = fat.len() as u32;
+        nodes[index].first_cluster = first;
+        fat.extend((first + 1..first + node_clusters as u32).chain([ END_OF_CHAIN ]));
+    }
+
+    let used_clusters = fat.len() as u64 - 2;
+    if used_clusters > clusters {
+        return Err(format!("the files need {} clusters, but the partition only has {}", used_clusters, clusters));
+    }
+
+    for &index in &order {
+        if nodes[index].source.is_none() {
+            directories.push(index);
+            encoded.push(directory(&nodes, index, options)?);
+        }
+    }
+
+    let data_start = offset + (RESERVED_SECTORS + FATS * fat_sectors) * SECTOR;
+    let cluster_offset = |cluster: u32| data_start + (cluster as u64 - 2) * SECTOR;
+
+    let boot_sector = boot_sector(sectors, fat_sectors, offset / SECTOR, options);
+    let fs_info = fs_info(clusters - used_clusters, fat.len() as u32);
+    for copy in [ 0, 6 ] {
+        write_at(file, offset + copy * SECTOR, &boot_sector).map_err(io)?;
+        write_at(file, offset + (copy + 1) * SECTOR, &fs_info).map_err(io)?;
+    }
+
+    let mut fat_bytes = fat.iter().flat_map(|entry| entry.to_le_bytes()).collect::<Vec<_>>();
+    fat_bytes.resize((fat_sectors * SECTOR) as usize, 0);
+    for copy in 0..FATS {
+        let fat_offset = offset + (RESERVED_SECTORS + copy * fat_sectors) * SECTOR;
+        write_at(file, fat_offset, &fat_bytes).map_err(io)?;
+    }
+
+    for (index, bytes) in directories.iter().zip(encoded) {
+        write_at(file, cluster_offset(nodes[*index].first_cluster), &bytes).map_err(io)?;
+    }
+
+    for node in nodes.iter().filter(|node| node.size > 0) {
+        if let Some(source) = &node.source {
+            let mut source_file = File::open(source).map_err(|e| format!("couldn't read {}: {}", source.display(), e))?;
+            file.seek(SeekFrom::Start(cluster_offset(node.first_cluster))).map_err(io)?;
+            io::copy(&mut source_file, file).map_err(io)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn add_file(nodes: &mut Vec<Node>, path: &str, source: PathBuf, size: u64) -> Result<(), String> {
+    let mut parts = path.split('/').filter(|part| !part.is_empty()).collect::<Vec<_>>();
+    let name = match parts.pop() {
+        Some(name) => name,
+        None => return Err(format!("invalid path in FAT partition: {:?}", path)),
+    };
+
+    let mut parent = 0;
+    for part in parts {
+        let existing = nodes[parent].children.iter().copied().find(|child| nodes[*child].name == part);
+        parent = match existing {
+            Some(dir) if nodes[dir].source.is_none() => dir,
+            Some(_) => return Err(format!("{} is both a file and a directory", part)),
+            None => {
+                let dir = nodes.len();
+                nodes.push(Node { name: part.to_string(), source: None, children: Vec::new(), parent, size: 0, first_cluster: 0 });
+                nodes[parent].children.push(dir);
+                dir
+            },
+        };
+    }
+
+    if nodes[parent].children.iter().any(|child| nodes[*child].name == name) {
+        return Err(format!("{} appears twice", path));
+    }
+
+    let index = nodes.len();
+    nodes.push(Node { name: name.to_string(), source: Some(source), children: Vec::new(), parent, size, first_cluster: 0 });
+    nodes[parent].children.push(index);
+    Ok(())
+}
+
+/// Encodes the entries of a directory, padded to a whole number of clusters.
+fn directory(nodes: &[Node], index: usize, options: &Options) -> Result<Vec<u8>, String> {
+    let node = &nodes[index];
+    let mut bytes = Vec::new();
+
+    if index == 0 {
+        bytes.extend(entry(&short_label(&options.label), ATTR_VOLUME_ID, 0, 0, options.mtime));
+    } else {
+        let parent_cluster = match node.parent {
+            // ".." refers to the root directory as cluster 0
+            0 => 0,
+            parent => nodes[parent].first_cluster,
+        };
+        bytes.extend(entry(b".          ", ATTR_DIRECTORY, node.first_cluster, 0, options.mtime));
+        bytes.extend(entry(b"..         ", ATTR_DIRECTORY, parent_cluster, 0, options.mtime));
+    }
+
+    let mut short_names = Vec::new();
+    for &child in &node.children {
+        let child = &nodes[child];
+        let (short_name, needs_long_name) = short_name(&child.name, &short_names);
+        short_names.push(short_name);
+
+        if needs_long_name {
+            bytes.extend(long_name_entries(&child.name, &short_name)?);
+        }
+
+        let (attributes, size) = match child.source {
+            Some(_) => (ATTR_ARCHIVE, child.size as u32),
+            None => (ATTR_DIRECTORY, 0),
+        };
+        bytes.extend(entry(&short_name, attributes, child.first_cluster, size, options.mtime));
+    }
+
+    // an empty entry marks the end of the directory
+    bytes.resize(bytes.len() + 32, 0);
+    let padded = (bytes.len() as u64).div_ceil(SECTOR) * SECTOR;
+    bytes.resize(padded as usize, 0);
+    Ok(bytes)
+}
+
+fn short_label(label: &str) -> [u8; 11] {
+    let mut bytes = [b' '; 11];
+    for (byte, c) in bytes.iter_mut().zip(label.bytes()) {
+        *byte = c.to_ascii_uppercase();
+    }
+    bytes
+}
+
+/// Derives a unique 8.3 name; returns whether a long name is needed too.
+fn short_name(name: &str, taken: &[[u8; 11]]) -> ([u8; 11], bool) {
+    let valid = |c: char| c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c);
+    let clean = |part: &str| part.chars()
+        .map(|c| c.to_ascii_uppercase())
+        .filter(|c| valid(*c))
+        .collect::<String>();
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, extension),
+        _ => (name, ""),
+    };
+
+    // names which only differ from their 8.3 form by case keep it, with a long name for the case
+    let upper = |part: &str| part.to_ascii_uppercase();
+    let fits = stem.len() <= 8 && extension.len() <= 3
+        && upper(stem).chars().all(valid) && upper(extension).chars().all(valid);
+    let exact = fits && upper(name) == name;
+
+    let stem = clean(stem);
+    let extension = clean(extension);
+
+    let make = |stem: &str| {
+        let mut bytes = [b' '; 11];
+        bytes[..stem.len().min(8)].copy_from_slice(&stem.as_bytes()[..stem.len().min(8)]);
+        bytes[8..8 + extension.len().min(3)].copy_from_slice(&extension.as_bytes()[..extension.len().min(3)]);
+        bytes
+    };
+
+    if fits && !taken.contains(&make(&stem)) {
+        return (make(&stem), !exact);
+    }
+
+    let mut counter = 1;
+    loop {
+        let suffix = format!("~{}", counter);
+        let basis = &stem[..stem.len().min(8 - suffix.len())];
+        let candidate = make(&format!("{}{}", basis, suffix));
+        if !taken.contains(&candidate) {
+            return (candidate, true);
+        }
+        counter += 1;
+    }
+}
+
+fn long_name_entries(name: &str, short_name: &[u8; 11]) -> Result<Vec<u8>, String> {
+    let checksum = short_name.iter().fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte));
+
+    let mut units = name.encode_utf16().collect::<Vec<_>>();
+    if units.len() > 255 {
+        return Err(format!("{} is too long for a FAT file name", name));
+    }
+    if units.len() % 13 != 0 {
+        units.push(0);
+    }
+    while units.len() % 13 != 0 {
+        units.push(0xffff);
+    }
+
+    let count = units.len() / 13;
+    let mut bytes = Vec::new();
+    // stored last part first
+    for (i, chunk) in units.chunks(13).enumerate().rev() {
+        let mut sequence = i as u8 + 1;
+        if i + 1 == count {
+            sequence |= 0x40;
+        }
+
+        let chars = chunk.iter().flat_map(|unit| unit.to_le_bytes()).collect::<Vec<_>>();
+        bytes.push(sequence);
+        bytes.extend_from_slice(&chars[0..10]);
+        bytes.extend_from_slice(&[ ATTR_LONG_NAME, 0, checksum ]);
+        bytes.extend_from_slice(&chars[10..22]);
+        bytes.extend_from_slice(&[ 0, 0 ]);
+        bytes.extend_from_slice(&chars[22..26]);
+    }
+
+    Ok(bytes)
+}
+
+/// Converts seconds since 1970 to FAT's date and time, which start in 1980.
+fn fat_date_time(mtime: u64) -> (u16, u16) {
+    // 1980-01-01
+    let mtime = mtime.max(315532800);
+    let days = mtime / 86400;
+    let seconds = mtime % 86400;
+
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = (year_of_era + era * 400 + (month <= 2) as u64).min(2107);
+
+    let date = ((year - 1980) << 9) | (month << 5) | day;
+    let time = ((seconds / 3600) << 11) | ((seconds / 60 % 60) << 5) | (seconds % 60 / 2);
+    (date as u16, time as u16)
+}
+
+fn entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32, mtime: u64) -> Vec<u8> {
+    let (date, time) = fat_date_time(mtime);
+
+    let mut entry = name.to_vec();
+    entry.push(attributes);
+    // reserved, creation time (tenths of seconds, time, date) and access date
+    entry.extend_from_slice(&[ 0, 0 ]);
+    entry.extend_from_slice(&time.to_le_bytes());
+    entry.extend_from_slice(&date.to_le_bytes());
+    entry.extend_from_slice(&date.to_le_bytes());
+    entry.extend_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry.extend_from_slice(&time.to_le_bytes());
+    entry.extend_from_slice(&date.to_le_bytes());
+    entry.extend_from_slice(&(cluster as u16).to_le_bytes());
+    entry.extend_from_slice(&size.to_le_bytes());
+    entry
+}
+
+fn boot_sector(sectors: u64, fat_sectors: u64, hidden_sectors: u64, options: &Options) -> Vec<u8> {
+    let mut sector = vec![ 0xeb, 0x58, 0x90 ];
+    sector.extend_from_slice(b"THESEUS ");
+    sector.extend_from_slice(&(SECTOR as u16).to_le_bytes());
+    // sectors per cluster
+    sector.push(1);
+    sector.extend_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    sector.push(FATS as u8);
+    // root entries and 16-bit sector count, unused by FAT32
+    sector.extend_from_slice(&[ 0, 0, 0, 0 ]);
+    // media descriptor, 16-bit FAT size
+    sector.extend_from_slice(&[ 0xf8, 0, 0 ]);
+    // sectors per track and heads
+    sector.extend_from_slice(&32u16.to_le_bytes());
+    sector.extend_from_slice(&64u16.to_le_bytes());
+    sector.extend_from_slice(&(hidden_sectors as u32).to_le_bytes());
+    sector.extend_from_slice(&(sectors as u32).to_le_bytes());
+
+    sector.extend_from_slice(&(fat_sectors as u32).to_le_bytes());
+    // flags and version
+    sector.extend_from_slice(&[ 0, 0, 0, 0 ]);
+    // root directory cluster, FSInfo and backup boot sector locations
+    sector.extend_from_slice(&2u32.to_le_bytes());
+    sector.extend_from_slice(&1u16.to_le_bytes());
+    sector.extend_from_slice(&6u16.to_le_bytes());
+    sector.extend_from_slice(&[0; 12]);
+    // drive number, reserved, extended boot signature
+    sector.extend_from_slice(&[ 0x80, 0, 0x29 ]);
+    sector.extend_from_slice(&options.volume_id.to_le_bytes());
+    sector.extend_from_slice(&short_label(&options.label));
+    sector.extend_from_slice(b"FAT32   ");
+
+    sector.resize(SECTOR as usize, 0);
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+    sector
+}
+
+fn fs_info(free_clusters: u64, next_free: u32) -> Vec<u8> {
+    let mut sector = vec![0; SECTOR as usize];
+    sector[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+    sector[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+    sector[488..492].copy_from_slice(&(free_clusters as u32).to_le_bytes());
+    sector[492..496].copy_from_slice(&next_free.to_le_bytes());
+    sector[508..512].copy_from_slice(&0xaa550000u32.to_le_bytes());
+    sector
+}
+
+fn write_at(file: &mut File, offset: u64, bytes: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::u16_le;
+    use crate::test_util::u32_le;
+    use crate::test_util::TempDir;
+
+    use std::fs::read;
+    use std::fs::write;
+
+    #[test]
+    fn short_names() {
+        assert_eq!(short_name("README.TXT", &[]), (*b"README  TXT", false));
+        // only the case differs: the 8.3 name is kept, with a long name
+        assert_eq!(short_name("readme.txt", &[]), (*b"README  TXT", true));
+        assert_eq!(short_name("Makefile", &[]), (*b"MAKEFILE   ", true));
+
+        let (first, needs_long) = short_name("very_long_name.text", &[]);
+        assert_eq!((&first, needs_long), (b"VERY_L~1TEX", true));
+        let (second, _) = short_name("very_long_name.texture", &[ first ]);
+        assert_eq!(&second, b"VERY_L~2TEX");
+
+        // collisions with an existing 8.3 name, and invalid characters
+        assert_eq!(short_name("readme.txt", &[ *b"README  TXT" ]).0, *b"README~1TXT");
+        assert_eq!(short_name("k#a+b.o", &[]), (*b"K#AB~1  O  ", true));
+    }
+
+    #[test]
+    fn long_names() {
+        let short = *b"README  TXT";
+        let entries = long_name_entries("readme.txt", &short).unwrap();
+        assert_eq!(entries.len(), 32);
+        assert_eq!(entries[0], 0x41);
+        assert_eq!(entries[11], ATTR_LONG_NAME);
+        assert_eq!(entries[13], 0x73);
+
+        // UCS-2 characters, then a terminator and padding
+        let units = [ 1..11, 14..26, 28..32 ].into_iter()
+            .flat_map(|range| entries[range].chunks(2).map(|unit| u16_le(unit, 0)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut expected = "readme.txt".encode_utf16().collect::<Vec<_>>();
+        expected.extend([ 0, 0xffff, 0xffff ]);
+        assert_eq!(units, expected);
+
+        // exactly 13 characters: no terminator
+        let entries = long_name_entries("thirteen_char", &short).unwrap();
+        assert_eq!(entries.len(), 32);
+        assert_eq!(u16_le(&entries, 30), 'r' as u16);
+
+        // the last part comes first
+        let entries = long_name_entries("fourteen_chars", &short).unwrap();
+        assert_eq!((entries.len(), entries[0], entries[32]), (64, 0x42, 0x01));
+        assert_eq!((u16_le(&entries, 1), u16_le(&entries, 3)), ('s' as u16, 0));
+        assert!(entries[13] == entries[32 + 13]);
+
+        assert!(long_name_entries(&"x".repeat(256), &short).is_err());
+    }
+
+    #[test]
+    fn date_time() {
+        // 1980-01-01 00:00:00, the earliest date
+        assert_eq!(fat_date_time(0), (0x21, 0));
+        assert_eq!(fat_date_time(315532800), (0x21, 0));
+        // 2000-02-29 12:34:56; seconds are halved
+        assert_eq!(fat_date_time(951827696), ((20 << 9) | (2 << 5) | 29, (12 << 11) | (34 << 5) | 28));
+        // 2107-12-31 23:59:58, the latest date
+        assert_eq!(fat_date_time(4354819198), ((127 << 9) | (12 << 5) | 31, (23 << 11) | (59 << 5) | 29));
+    }
+
+    /// Reads the clusters of the chain starting at `cluster`.
+    fn read_chain(image: &[u8], cluster: u32) -> Vec<u8> {
+        let reserved = u16_le(image, 14) as usize;
+        let fat_sectors = u32_le(image, 36) as usize;
+        let fat = &image[reserved * 512..][..fat_sectors * 512];
+        let data = (reserved + 2 * fat_sectors) * 512;
+
+        let mut bytes = Vec::new();
+        let mut cluster = cluster;
+        while cluster < 0x0ffffff8 {
+            bytes.extend_from_slice(&image[data + (cluster as usize - 2) * 512..][..512]);
+            cluster = u32_le(fat, cluster as usize * 4) & 0x0fffffff;
+        }
+        bytes
+    }
+
+    /// Lists (long or 8.3 name, attributes, first cluster, size) of the entries of a directory,
+    /// checking that long names belong to the entry following them.
+    fn list(directory: &[u8]) -> Vec<(String, u8, u32, u32)> {
+        let mut entries = Vec::new();
+        let mut long_name = Vec::new();
+        let mut checksum = None;
+
+        for entry in directory.chunks(32).take_while(|entry| entry[0] != 0) {
+            if entry[11] == ATTR_LONG_NAME {
+                let units = [ 1..11, 14..26, 28..32 ].into_iter()
+                    .flat_map(|range| entry[range].chunks(2).map(|unit| u16_le(unit, 0)).collect::<Vec<_>>())
+                    .take_while(|unit| *unit != 0)
+                    .collect::<Vec<_>>();
+                long_name.splice(0..0, units);
+                checksum = Some(entry[13]);
+                continue;
+            }
+
+            let short = &entry[0..11];
+            let name = match checksum.take() {
+                Some(checksum) => {
+                    assert_eq!(checksum, short.iter().fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte)));
+                    String::from_utf16(&long_name).unwrap()
+                },
+                None => {
+                    let stem = String::from_utf8_lossy(&short[..8]).trim_end().to_string();
+                    let extension = String::from_utf8_lossy(&short[8..]).trim_end().to_string();
+                    match extension.is_empty() {
+                        true => stem,
+                        false => format!("{}.{}", stem, extension),
+                    }
+                },
+            };
+            long_name.clear();
+
+            let cluster = ((u16_le(entry, 20) as u32) << 16) | u16_le(entry, 26) as u32;
+            entries.push((name, entry[11], cluster, u32_le(entry, 28)));
+        }
+
+        entries
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("fat32");
+        let kernel = dir.join("kernel");
+        let module = dir.join("module");
+        write(&kernel, vec![0xaa; 1500]).unwrap();
+        write(&module, b"module").unwrap();
+
+        let files = [
+            ("boot/kernel.elf".to_string(), kernel),
+            ("boot/modules/k#a_long-module.name.o".to_string(), module),
+            ("EFI/BOOT/BOOTX64.EFI".to_string(), dir.join("module")),
+        ];
+        let options = Options { label: "THESEUS".into(), volume_id: 0x1234_5678, mtime: 0 };
+        let image_path = dir.join("image");
+        let mut file = File::create(&image_path).unwrap();
+        let sectors = minimum_sectors(&[ 1500, 6, 6 ]);
+        file.set_len(sectors * SECTOR).unwrap();
+        write_fat32(&mut file, 0, sectors, &files, &options).unwrap();
+        drop(file);
+
+        let image = read(&image_path).unwrap();
+
+        // boot sector and its backup, FAT copies
+        assert_eq!(&image[510..512], [ 0x55, 0xaa ]);
+        assert_eq!(&image[82..90], b"FAT32   ");
+        assert_eq!(u32_le(&image, 67), 0x1234_5678);
+        assert!(image[..1024] == image[6 * 512..][..1024]);
+        let fat_size = u32_le(&image, 36) as usize * 512;
+        assert!(image[32 * 512..][..fat_size] == image[32 * 512 + fat_size..][..fat_size]);
+
+        let root = list(&read_chain(&image, u32_le(&image, 44)));
+        let names = root.iter().map(|(name, attributes, _, _)| (name.as_str(), *attributes)).collect::<Vec<_>>();
+        assert_eq!(names, [ ("THESEUS", ATTR_VOLUME_ID), ("boot", ATTR_DIRECTORY), ("EFI", ATTR_DIRECTORY) ]);
+
+        let find = |entries: &[(String, u8, u32, u32)], name: &str| {
+            entries.iter().find(|entry| entry.0 == name).cloned().unwrap()
+        };
+        let boot = list(&read_chain(&image, find(&root, "boot").2));
+        assert_eq!((boot[0].0.as_str(), boot[1].0.as_str()), (".", ".."));
+        assert_eq!(boot[1].2, 0);
+
+        let (_, _, cluster, size) = find(&boot, "kernel.elf");
+        assert!(read_chain(&image, cluster)[..size as usize] == [0xaa; 1500]);
+
+        let modules = list(&read_chain(&image, find(&boot, "modules").2));
+        let (_, _, cluster, size) = find(&modules, "k#a_long-module.name.o");
+        assert_eq!(&read_chain(&image, cluster)[..size as usize], b"module");
+
+        let efi = list(&read_chain(&image, find(&root, "EFI").2));
+        let efi_boot = list(&read_chain(&image, find(&efi, "BOOT").2));
+        assert_eq!(find(&efi_boot, "BOOTX64.EFI").3, 6);
+    }
+}
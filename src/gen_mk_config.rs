@@ -25,6 +25,7 @@ pub fn process(config: &Config) {
         let content = match opt_default(&key) {
             Value::String(_) => config.str(&key),
             Value::Boolean(_) => config.bool(&key).to_string(),
+            Value::Integer(_) => config.int(&key).to_string(),
             Value::Array(_) => config.vec(&key).join(" "),
             _ => oops!(stage, "invalid property type for key {}", &key),
         };
@@ -0,0 +1,873 @@
+//! Writes ISO 9660 images with El Torito boot entries, like `xorriso -as mkisofs` does for limine.
+//!
+//! File names are mangled to ISO 9660 level 2 names; the original names are kept
+//! in Rock Ridge `NM` entries, which limine and most systems read. The extension is announced
+//! by an `ER` entry, in a continuation area of the root's `.` record.
+//! All timestamps, owners and permissions are fixed, so that images are reproducible.
+
+use std::fs::metadata;
+use std::fs::read;
+use std::fs::read_dir;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+const SECTOR: u64 = 2048;
+/// The first sectors are left for the MBR and for bootloaders
+const SYSTEM_AREA_SECTORS: u32 = 16;
+/// The system use area of a directory record must fit in 255 bytes with the rest
+const MAX_RECORD: usize = 255;
+
+/// The `ER` entry identifying Rock Ridge, with the texts RRIP 1.09 recommends
+const RRIP_ID: &str = "RRIP_1991A";
+const RRIP_DESCRIPTOR: &str = "THE ROCK RIDGE INTERCHANGE PROTOCOL PROVIDES SUPPORT FOR POSIX FILE SYSTEM SEMANTICS";
+const RRIP_SOURCE: &str = "PLEASE CONTACT DISC PUBLISHER FOR SPECIFICATION SOURCE.  \
+    SEE PUBLISHER IDENTIFIER IN PRIMARY VOLUME DESCRIPTOR FOR CONTACT INFORMATION.";
+
+pub struct Options {
+    pub volume_id: String,
+    /// Seconds since 1970, for all timestamps
+    pub mtime: u64,
+    /// Path, relative to the root, of a no-emulation BIOS boot image
+    /// which gets a boot info table (`-boot-info-table`)
+    pub bios_boot: Option<String>,
+    /// Path, relative to the root, of an EFI system partition image;
+    /// it's also made available as an MBR partition for USB boot
+    pub efi_boot: Option<String>,
+}
+
+struct Node {
+    name: String,
+    iso_name: String,
+    path: PathBuf,
+    /// Directory entries, as indices of nodes, or None for files
+    children: Option<Vec<usize>>,
+    parent: usize,
+    size: u64,
+    lba: u32,
+}
+
+impl Node {
+    fn is_dir(&self) -> bool {
+        self.children.is_some()
+    }
+}
+
+/// Creates an image at `output` with the contents of the `root` directory.
+pub fn write_iso(root: &Path, output: &Path, options: &Options) -> Result<(), String> {
+    let mut nodes = vec![ Node {
+        name: String::new(),
+        iso_name: String::new(),
+        path: root.to_path_buf(),
+        children: Some(Vec::new()),
+        parent: 0,
+        size: 0,
+        lba: 0,
+    } ];
+    add_children(&mut nodes, 0).map_err(|e| format!("couldn't list {}: {}", root.display(), e))?;
+
+    // sizes are 32-bit fields
+    if let Some(node) = nodes.iter().find(|node| node.size > u32::MAX as u64) {
+        return Err(format!("{} is larger than 4 GiB, which ISO 9660 doesn't support", node.path.display()));
+    }
+
+    let find = |nodes: &[Node], path: &Option<String>| -> Result<Option<usize>, String> {
+        match path {
+            Some(path) => find_node(nodes, path)
+                .filter(|index| !nodes[*index].is_dir())
+                .map(Some)
+                .ok_or_else(|| format!("boot image {} isn't in {}", path, root.display())),
+            None => Ok(None),
+        }
+    };
+    let bios_boot = find(&nodes, &options.bios_boot)?;
+    let efi_boot = find(&nodes, &options.efi_boot)?;
+
+    // directories in breadth-first order, as required by path tables
+    let mut directories = vec![ 0 ];
+    let mut i = 0;
+    while i < directories.len() {
+        let children = nodes[directories[i]].children.clone().unwrap();
+        directories.extend(children.into_iter().filter(|child| nodes[*child].is_dir()));
+        i += 1;
+    }
+
+    // layout: system area, volume descriptors, path tables, boot catalog, directories,
+    // continuation area of the root (readers expect it after the directory), files
+    let boot = bios_boot.is_some() || efi_boot.is_some();
+    let mut lba = SYSTEM_AREA_SECTORS + 2 + u32::from(boot);
+
+    let path_table_size = path_table(&nodes, &directories, false).len();
+    let path_table_size = u32::try_from(path_table_size).map_err(|_| "the path tables are too large".to_string())?;
+    let path_table_sectors = sectors(path_table_size as u64)?;
+    let l_path_table = lba;
+    let m_path_table = lba + path_table_sectors;
+    lba += 2 * path_table_sectors;
+
+    let boot_catalog = lba;
+    lba += u32::from(boot);
+
+    // the size of directories doesn't depend on where the continuation area is
+    for &dir in &directories {
+        let size = directory(&nodes, dir, options.mtime, 0)?.len() as u64;
+        nodes[dir].size = size;
+        nodes[dir].lba = lba;
+        lba = advance(lba, size)?;
+    }
+
+    let continuation = lba;
+    lba += 1;
+
+    for node in nodes.iter_mut().filter(|node| !node.is_dir()) {
+        node.lba = lba;
+        lba = advance(lba, node.size)?;
+    }
+    let volume_size = lba;
+
+    let file = File::create(output).map_err(|e| format!("couldn't create {}: {}", output.display(), e))?;
+    let mut writer = Writer { inner: BufWriter::new(file), position: 0 };
+    let io = |e: io::Error| format!("couldn't write {}: {}", output.display(), e);
+
+    writer.write_all(&mbr(efi_boot.map(|efi| &nodes[efi]))?).map_err(io)?;
+    writer.pad_to(SYSTEM_AREA_SECTORS).map_err(io)?;
+
+    writer.write_all(&primary_volume_descriptor(
+        &nodes, options, volume_size, path_table_size, l_path_table, m_path_table,
+    )).map_err(io)?;
+    if boot {
+        writer.write_all(&boot_record_volume_descriptor(boot_catalog)).map_err(io)?;
+    }
+    writer.write_all(&volume_descriptor_set_terminator()).map_err(io)?;
+
+    writer.write_all(&path_table(&nodes, &directories, false)).map_err(io)?;
+    writer.pad_to(m_path_table).map_err(io)?;
+    writer.write_all(&path_table(&nodes, &directories, true)).map_err(io)?;
+    writer.pad_to(boot_catalog).map_err(io)?;
+
+    if boot {
+        let bios_boot = bios_boot.map(|index| &nodes[index]);
+        let efi_boot = efi_boot.map(|index| &nodes[index]);
+        writer.write_all(&boot_catalog_sector(bios_boot, efi_boot)).map_err(io)?;
+    }
+
+    for &dir in &directories {
+        writer.pad_to(nodes[dir].lba).map_err(io)?;
+        writer.write_all(&directory(&nodes, dir, options.mtime, continuation)?).map_err(io)?;
+    }
+
+    writer.pad_to(continuation).map_err(io)?;
+    writer.write_all(&rock_ridge_extension()).map_err(io)?;
+
+    for (index, node) in nodes.iter().enumerate() {
+        if node.is_dir() {
+            continue;
+        }
+
+        writer.pad_to(node.lba).map_err(io)?;
+        if Some(index) == bios_boot {
+            let mut contents = read(&node.path).map_err(|e| format!("couldn't read {}: {}", node.path.display(), e))?;
+            add_boot_info_table(&mut contents, node.lba)?;
+            writer.write_all(&contents).map_err(io)?;
+        } else {
+            let mut file = File::open(&node.path).map_err(|e| format!("couldn't read {}: {}", node.path.display(), e))?;
+            let copied = io::copy(&mut file, &mut writer).map_err(io)?;
+            if copied != node.size {
+                return Err(format!("{} changed while the image was written", node.path.display()));
+            }
+        }
+    }
+
+    writer.pad_to(volume_size).map_err(io)?;
+    writer.inner.flush().map_err(io)
+}
+
+struct Writer {
+    inner: BufWriter<File>,
+    position: u64,
+}
+
+impl Writer {
+    /// Fills the image with zeros up to the given sector.
+    fn pad_to(&mut self, lba: u32) -> io::Result<()> {
+        let target = lba as u64 * SECTOR;
+        let padding = match target.checked_sub(self.position) {
+            Some(padding) => padding,
+            None => return Err(io::Error::other(format!(
+                "sector {} was laid out at byte {:#x}, but {:#x} bytes are already written",
+                lba, target, self.position,
+            ))),
+        };
+        self.write_all(&vec![0; padding as usize])
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn sectors(size: u64) -> Result<u32, String> {
+    u32::try_from(size.div_ceil(SECTOR)).map_err(|_| "the image would be larger than 8 TiB".to_string())
+}
+
+/// The sector following `size` bytes at `lba`.
+fn advance(lba: u32, size: u64) -> Result<u32, String> {
+    sectors(size)?.checked_add(lba).ok_or_else(|| "the image would be larger than 8 TiB".to_string())
+}
+
+/// Adds the contents of a directory to the tree, sorted by name.
+fn add_children(nodes: &mut Vec<Node>, parent: usize) -> io::Result<()> {
+    let mut entries = read_dir(&nodes[parent].path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut children = Vec::new();
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // follow symbolic links
+        let metadata = metadata(entry.path())?;
+
+        let index = nodes.len();
+        nodes.push(Node {
+            iso_name: String::new(),
+            name,
+            path: entry.path(),
+            children: metadata.is_dir().then(Vec::new),
+            parent,
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            lba: 0,
+        });
+        children.push(index);
+
+        if metadata.is_dir() {
+            add_children(nodes, index)?;
+        }
+    }
+
+    assign_iso_names(nodes, &children);
+    // directory records must be sorted by their ISO 9660 name
+    children.sort_by(|a, b| nodes[*a].iso_name.cmp(&nodes[*b].iso_name));
+    nodes[parent].children = Some(children);
+
+    Ok(())
+}
+
+/// Derives unique level 2 names (d-characters, 30 characters at most) from the original names.
+fn assign_iso_names(nodes: &mut [Node], children: &[usize]) {
+    let mut taken = Vec::new();
+
+    for &child in children {
+        let node = &nodes[child];
+        let mangle = |part: &str| part.chars()
+            .map(|c| match c.to_ascii_uppercase() {
+                c @ ('A'..='Z' | '0'..='9' | '_') => c,
+                _ => '_',
+            })
+            .collect::<String>();
+
+        let (stem, extension) = match node.name.rsplit_once('.') {
+            Some((stem, extension)) if !node.is_dir() && !stem.is_empty() => (mangle(stem), mangle(extension)),
+            _ => (mangle(&node.name), String::new()),
+        };
+        let extension = &extension[..extension.len().min(8)];
+
+        let make_name = |suffix: &str| {
+            let room = 30 - extension.len() - (!extension.is_empty()) as usize - suffix.len();
+            let stem = &stem[..stem.len().min(room)];
+            match extension.is_empty() {
+                true => format!("{}{}", stem, suffix),
+                false => format!("{}{}.{}", stem, suffix, extension),
+            }
+        };
+
+        let mut iso_name = make_name("");
+        let mut counter = 0;
+        while taken.contains(&iso_name) {
+            counter += 1;
+            iso_name = make_name(&format!("_{}", counter));
+        }
+
+        taken.push(iso_name.clone());
+        nodes[child].iso_name = iso_name;
+    }
+}
+
+fn find_node(nodes: &[Node], path: &str) -> Option<usize> {
+    let mut index = 0;
+    for part in path.split('/').filter(|part| !part.is_empty()) {
+        let children = nodes[index].children.as_ref()?;
+        index = *children.iter().find(|child| nodes[**child].name == part)?;
+    }
+    Some(index)
+}
+
+fn both_u16(value: u16) -> [u8; 4] {
+    let (le, be) = (value.to_le_bytes(), value.to_be_bytes());
+    [ le[0], le[1], be[0], be[1] ]
+}
+
+fn both_u32(value: u32) -> [u8; 8] {
+    let (le, be) = (value.to_le_bytes(), value.to_be_bytes());
+    [ le[0], le[1], le[2], le[3], be[0], be[1], be[2], be[3] ]
+}
+
+/// Converts seconds since 1970 to (year, month, day, hour, minute, second).
+fn civil_time(mtime: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let days = mtime / 86400;
+    let seconds = mtime % 86400;
+
+    // Howard Hinnant's days_from_civil, reversed
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+
+    (year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// The 17-byte date format of volume descriptors
+fn volume_date(mtime: u64) -> Vec<u8> {
+    let (year, month, day, hour, minute, second) = civil_time(mtime);
+    let mut date = format!("{:04}{:02}{:02}{:02}{:02}{:02}00", year, month, day, hour, minute, second).into_bytes();
+    // GMT
+    date.push(0);
+    date
+}
+
+/// The 7-byte date format of directory records
+fn record_date(mtime: u64) -> [u8; 7] {
+    let (year, month, day, hour, minute, second) = civil_time(mtime);
+    [ (year - 1900) as u8, month as u8, day as u8, hour as u8, minute as u8, second as u8, 0 ]
+}
+
+/// Encodes a directory record with its Rock Ridge entries.
+///
+/// `name` is `[0]` for `.` and `[1]` for `..`. The root's `.` record gets the
+/// sector of the continuation area holding the `ER` entry.
+fn directory_record(node: &Node, name: &[u8], rock_ridge_name: Option<&str>, continuation: Option<u32>, mtime: u64) -> Result<Vec<u8>, String> {
+    let mut record = vec![ 0, 0 ];
+    record.extend_from_slice(&both_u32(node.lba));
+    let size = u32::try_from(node.size).map_err(|_| format!("{} is larger than 4 GiB", node.path.display()))?;
+    record.extend_from_slice(&both_u32(size));
+    record.extend_from_slice(&record_date(mtime));
+    record.push(if node.is_dir() { 0x02 } else { 0x00 });
+    record.extend_from_slice(&[ 0, 0 ]);
+    record.extend_from_slice(&both_u16(1));
+    record.push(name.len() as u8);
+    record.extend_from_slice(name);
+    if record.len() % 2 == 1 {
+        record.push(0);
+    }
+
+    // System Use Sharing Protocol indicator, which must come first
+    if continuation.is_some() {
+        record.extend_from_slice(&[ b'S', b'P', 7, 1, 0xbe, 0xef, 0 ]);
+    }
+
+    let (mode, links) = match node.is_dir() {
+        true => (0o040755, 2),
+        false => (0o100644, 1),
+    };
+    record.extend_from_slice(&[ b'P', b'X', 36, 1 ]);
+    record.extend_from_slice(&both_u32(mode));
+    record.extend_from_slice(&both_u32(links));
+    // uid and gid
+    record.extend_from_slice(&both_u32(0));
+    record.extend_from_slice(&both_u32(0));
+
+    if let Some(name) = rock_ridge_name {
+        record.extend_from_slice(&[ b'N', b'M', 5 + name.len() as u8, 1, 0 ]);
+        record.extend_from_slice(name.as_bytes());
+    }
+
+    // the ER entry doesn't fit in the record itself
+    if let Some(lba) = continuation {
+        record.extend_from_slice(&[ b'C', b'E', 28, 1 ]);
+        record.extend_from_slice(&both_u32(lba));
+        record.extend_from_slice(&both_u32(0));
+        record.extend_from_slice(&both_u32(rock_ridge_extension_entry().len() as u32));
+    }
+
+    if record.len() > MAX_RECORD {
+        return Err(format!("the name of {} is too long", node.path.display()));
+    }
+
+    record[0] = record.len() as u8;
+    Ok(record)
+}
+
+/// The `ER` entry announcing Rock Ridge.
+fn rock_ridge_extension_entry() -> Vec<u8> {
+    let mut entry = vec![ b'E', b'R', 0, 1, RRIP_ID.len() as u8, RRIP_DESCRIPTOR.len() as u8, RRIP_SOURCE.len() as u8, 1 ];
+    entry.extend_from_slice(RRIP_ID.as_bytes());
+    entry.extend_from_slice(RRIP_DESCRIPTOR.as_bytes());
+    entry.extend_from_slice(RRIP_SOURCE.as_bytes());
+    entry[2] = entry.len() as u8;
+    entry
+}
+
+/// The continuation area of the root's `.` record, holding the `ER` entry.
+fn rock_ridge_extension() -> Vec<u8> {
+    let mut sector = rock_ridge_extension_entry();
+    sector.resize(SECTOR as usize, 0);
+    sector
+}
+
+/// Encodes the records of a directory; records don't cross sector boundaries.
+///
+/// `continuation` is the sector of the root's continuation area.
+fn directory(nodes: &[Node], index: usize, mtime: u64, continuation: u32) -> Result<Vec<u8>, String> {
+    let node = &nodes[index];
+
+    let mut records = vec![
+        directory_record(node, &[0], None, Some(continuation).filter(|_| index == 0), mtime)?,
+        directory_record(&nodes[node.parent], &[1], None, None, mtime)?,
+    ];
+    for &child in node.children.as_ref().unwrap() {
+        let child = &nodes[child];
+        let iso_name = match child.is_dir() {
+            true => child.iso_name.clone(),
+            false => format!("{};1", child.iso_name),
+        };
+        records.push(directory_record(child, iso_name.as_bytes(), Some(&child.name), None, mtime)?);
+    }
+
+    let mut bytes = Vec::new();
+    for record in records {
+        let used = bytes.len() % SECTOR as usize;
+        if used + record.len() > SECTOR as usize {
+            bytes.resize(bytes.len() + SECTOR as usize - used, 0);
+        }
+        bytes.extend_from_slice(&record);
+    }
+    bytes.resize(sectors(bytes.len() as u64)? as usize * SECTOR as usize, 0);
+
+    Ok(bytes)
+}
+
+fn path_table(nodes: &[Node], directories: &[usize], big_endian: bool) -> Vec<u8> {
+    let mut table = Vec::new();
+
+    for &dir in directories {
+        let node = &nodes[dir];
+        let name = match dir {
+            0 => vec![0],
+            _ => node.iso_name.as_bytes().to_vec(),
+        };
+        // directories are numbered from 1, in the order of the table
+        let parent = directories.iter().position(|dir| *dir == node.parent).unwrap() as u16 + 1;
+
+        table.push(name.len() as u8);
+        table.push(0);
+        match big_endian {
+            true => {
+                table.extend_from_slice(&node.lba.to_be_bytes());
+                table.extend_from_slice(&parent.to_be_bytes());
+            },
+            false => {
+                table.extend_from_slice(&node.lba.to_le_bytes());
+                table.extend_from_slice(&parent.to_le_bytes());
+            },
+        }
+        table.extend_from_slice(&name);
+        if name.len() % 2 == 1 {
+            table.push(0);
+        }
+    }
+
+    table
+}
+
+fn padded(string: &str, len: usize) -> Vec<u8> {
+    let mut bytes = string.as_bytes().to_vec();
+    bytes.resize(len, b' ');
+    bytes
+}
+
+fn primary_volume_descriptor(
+    nodes: &[Node],
+    options: &Options,
+    volume_size: u32,
+    path_table_size: u32,
+    l_path_table: u32,
+    m_path_table: u32,
+) -> Vec<u8> {
+    let mut descriptor = vec![ 1 ];
+    descriptor.extend_from_slice(b"CD001");
+    descriptor.extend_from_slice(&[ 1, 0 ]);
+    descriptor.extend_from_slice(&padded("", 32));
+    descriptor.extend_from_slice(&padded(&options.volume_id, 32));
+    descriptor.extend_from_slice(&[0; 8]);
+    descriptor.extend_from_slice(&both_u32(volume_size));
+    descriptor.extend_from_slice(&[0; 32]);
+    // volume set size, volume sequence number and logical block size
+    descriptor.extend_from_slice(&both_u16(1));
+    descriptor.extend_from_slice(&both_u16(1));
+    descriptor.extend_from_slice(&both_u16(SECTOR as u16));
+    descriptor.extend_from_slice(&both_u32(path_table_size));
+    descriptor.extend_from_slice(&l_path_table.to_le_bytes());
+    descriptor.extend_from_slice(&0u32.to_le_bytes());
+    descriptor.extend_from_slice(&m_path_table.to_be_bytes());
+    descriptor.extend_from_slice(&0u32.to_be_bytes());
+
+    // the root directory record doesn't have system use entries here
+    let root = directory_record(&nodes[0], &[0], None, None, options.mtime).unwrap();
+    descriptor.extend_from_slice(&root[..34]);
+    descriptor[156] = 34;
+
+    // volume set, publisher, data preparer and application identifiers
+    descriptor.extend_from_slice(&padded("", 128 * 4));
+    // copyright, abstract and bibliographic file identifiers
+    descriptor.extend_from_slice(&padded("", 37 * 3));
+
+    let date = volume_date(options.mtime);
+    descriptor.extend_from_slice(&date);
+    descriptor.extend_from_slice(&date);
+    // no expiration date
+    descriptor.extend_from_slice(b"0000000000000000\0");
+    descriptor.extend_from_slice(&date);
+
+    // file structure version
+    descriptor.push(1);
+    descriptor.resize(SECTOR as usize, 0);
+    descriptor
+}
+
+fn boot_record_volume_descriptor(boot_catalog: u32) -> Vec<u8> {
+    let mut descriptor = vec![ 0 ];
+    descriptor.extend_from_slice(b"CD001");
+    descriptor.push(1);
+    let mut system_id = b"EL TORITO SPECIFICATION".to_vec();
+    system_id.resize(32, 0);
+    descriptor.extend_from_slice(&system_id);
+    descriptor.extend_from_slice(&[0; 32]);
+    descriptor.extend_from_slice(&boot_catalog.to_le_bytes());
+    descriptor.resize(SECTOR as usize, 0);
+    descriptor
+}
+
+fn volume_descriptor_set_terminator() -> Vec<u8> {
+    let mut descriptor = vec![ 255 ];
+    descriptor.extend_from_slice(b"CD001");
+    descriptor.push(1);
+    descriptor.resize(SECTOR as usize, 0);
+    descriptor
+}
+
+/// A no-emulation boot entry, loading `sector_count` 512-byte sectors of `node`.
+fn boot_entry(node: &Node, sector_count: u16) -> Vec<u8> {
+    let mut entry = vec![ 0x88, 0 ];
+    // load segment (0 means 0x7c0), system type and unused byte
+    entry.extend_from_slice(&[ 0, 0, 0, 0 ]);
+    entry.extend_from_slice(&sector_count.to_le_bytes());
+    entry.extend_from_slice(&node.lba.to_le_bytes());
+    entry.resize(32, 0);
+    entry
+}
+
+fn boot_catalog_sector(bios_boot: Option<&Node>, efi_boot: Option<&Node>) -> Vec<u8> {
+    // validation entry, for the x86 platform
+    let mut catalog = vec![ 1, 0, 0, 0 ];
+    catalog.resize(28, 0);
+    catalog.extend_from_slice(&[ 0, 0, 0x55, 0xaa ]);
+    let sum = catalog.chunks(2).fold(0u16, |sum, word| sum.wrapping_add(u16::from_le_bytes([ word[0], word[1] ])));
+    catalog[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+    // initial/default entry: BIOS boot, or nothing
+    match bios_boot {
+        // like `-boot-load-size 4`
+        Some(node) => catalog.extend_from_slice(&boot_entry(node, 4)),
+        None => catalog.extend_from_slice(&[0; 32]),
+    }
+
+    if let Some(node) = efi_boot {
+        // final section header for the EFI platform, with one entry
+        let mut header = vec![ 0x91, 0xef ];
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.resize(32, 0);
+        catalog.extend_from_slice(&header);
+
+        let sector_count = u16::try_from(node.size.div_ceil(512)).unwrap_or(0);
+        catalog.extend_from_slice(&boot_entry(node, sector_count));
+    }
+
+    catalog.resize(SECTOR as usize, 0);
+    catalog
+}
+
+/// Patches the boot info table which `-boot-info-table` adds to BIOS boot images.
+fn add_boot_info_table(contents: &mut [u8], lba: u32) -> Result<(), String> {
+    if contents.len() < 64 {
+        return Err("the BIOS boot image is too small for a boot info table".into());
+    }
+
+    let checksum = contents[64..]
+        .chunks(4)
+        .map(|word| {
+            let mut bytes = [0; 4];
+            bytes[..word.len()].copy_from_slice(word);
+            u32::from_le_bytes(bytes)
+        })
+        .fold(0u32, u32::wrapping_add);
+
+    let mut table = Vec::with_capacity(56);
+    table.extend_from_slice(&SYSTEM_AREA_SECTORS.to_le_bytes());
+    table.extend_from_slice(&lba.to_le_bytes());
+    let len = u32::try_from(contents.len()).map_err(|_| "the BIOS boot image is larger than 4 GiB".to_string())?;
+    table.extend_from_slice(&len.to_le_bytes());
+    table.extend_from_slice(&checksum.to_le_bytes());
+    table.resize(56, 0);
+    contents[8..64].copy_from_slice(&table);
+
+    Ok(())
+}
+
+/// An MBR whose only partition is the EFI system partition image, if any,
+/// so that the image also boots on UEFI systems when written to a USB drive.
+fn mbr(efi_boot: Option<&Node>) -> Result<Vec<u8>, String> {
+    let mut mbr = vec![0; 512];
+
+    if let Some(node) = efi_boot {
+        let too_far = || format!("{} is too far into the image for an MBR partition", node.path.display());
+        let start = node.lba.checked_mul(SECTOR as u32 / 512).ok_or_else(too_far)?;
+        let sectors = u32::try_from(node.size.div_ceil(512)).map_err(|_| too_far())?;
+
+        let entry = &mut mbr[446..462];
+        // not bootable; CHS addresses are unused
+        entry[1..4].copy_from_slice(&[ 0xfe, 0xff, 0xff ]);
+        entry[4] = 0xef;
+        entry[5..8].copy_from_slice(&[ 0xfe, 0xff, 0xff ]);
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+    Ok(mbr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::u16_le;
+    use crate::test_util::u32_le;
+    use crate::test_util::TempDir;
+
+    use std::fs::create_dir_all;
+    use std::fs::write;
+
+    /// Reads a both-endian u32, checking that both halves agree.
+    fn u32_both(bytes: &[u8], offset: usize) -> u32 {
+        let le = u32_le(bytes, offset);
+        assert_eq!(le.to_be_bytes(), bytes[offset + 4..offset + 8], "both-endian field at {:#x}", offset);
+        le
+    }
+
+    fn sector(image: &[u8], lba: u32) -> &[u8] {
+        &image[lba as usize * SECTOR as usize..][..SECTOR as usize]
+    }
+
+    /// Writes an image of a small tree with both boot images, and returns it.
+    fn sample_image(name: &str) -> Vec<u8> {
+        let dir = TempDir::new(name);
+        let root = dir.join("root");
+        create_dir_all(root.join("boot/modules")).unwrap();
+        write(root.join("limine-cd.bin"), vec![0xfa; 5000]).unwrap();
+        write(root.join("limine-cd-efi.bin"), vec![0xef; 3000]).unwrap();
+        write(root.join("boot/kernel.bin"), b"kernel").unwrap();
+        write(root.join("boot/modules/k#a_long-module.name.o"), b"module").unwrap();
+        create_dir_all(root.join("empty")).unwrap();
+
+        let output = dir.join("image.iso");
+        let options = Options {
+            volume_id: "THESEUS".into(),
+            mtime: 0,
+            bios_boot: Some("limine-cd.bin".into()),
+            efi_boot: Some("limine-cd-efi.bin".into()),
+        };
+        write_iso(&root, &output, &options).unwrap();
+
+        read(&output).unwrap()
+    }
+
+    /// Finds the (extent, size) of `name` in the directory at `lba`.
+    fn lookup(image: &[u8], lba: u32, name: &[u8]) -> Option<(u32, u32)> {
+        let mut offset = lba as usize * SECTOR as usize;
+        loop {
+            let len = image[offset] as usize;
+            if len == 0 {
+                return None;
+            }
+            let record = &image[offset..offset + len];
+            if &record[33..33 + record[32] as usize] == name {
+                return Some((u32_both(record, 2), u32_both(record, 10)));
+            }
+            offset += len;
+        }
+    }
+
+    #[test]
+    fn primary_volume_descriptor() {
+        let image = sample_image("pvd");
+        let pvd = sector(&image, 16);
+
+        assert_eq!(pvd[0], 1);
+        assert_eq!(&pvd[1..6], b"CD001");
+        assert_eq!(&pvd[40..72], &padded("THESEUS", 32)[..]);
+        assert_eq!(u32_both(pvd, 80) as usize * SECTOR as usize, image.len());
+        assert_eq!(u16_le(pvd, 128), SECTOR as u16);
+
+        // the root directory record
+        assert_eq!(pvd[156], 34);
+        let root = u32_both(pvd, 158);
+        assert_eq!(u32_both(pvd, 166), SECTOR as u32);
+        assert!(lookup(&image, root, &[0]).is_some());
+        assert!(lookup(&image, root, b"BOOT").is_some());
+
+        assert_eq!(&pvd[813..830], b"1970010100000000\0");
+        assert_eq!(pvd[881], 1);
+
+        let terminator = sector(&image, 18);
+        assert_eq!((terminator[0], &terminator[1..6]), (255, &b"CD001"[..]));
+    }
+
+    #[test]
+    fn path_tables() {
+        let image = sample_image("path-tables");
+        let pvd = sector(&image, 16);
+        let size = u32_both(pvd, 132) as usize;
+        let l_table = &image[u32_le(pvd, 140) as usize * SECTOR as usize..][..size];
+        let m_table = &image[u32::from_be_bytes(pvd[148..152].try_into().unwrap()) as usize * SECTOR as usize..][..size];
+
+        // (name, extent, parent number) of each entry, in order
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let name_len = l_table[offset] as usize;
+            let extent = u32_le(l_table, offset + 2);
+            let parent = u16_le(l_table, offset + 6);
+            let name = l_table[offset + 8..offset + 8 + name_len].to_vec();
+
+            assert_eq!(m_table[offset], l_table[offset]);
+            assert_eq!(m_table[offset + 2..offset + 6], extent.to_be_bytes());
+            assert_eq!(m_table[offset + 6..offset + 8], parent.to_be_bytes());
+            assert_eq!(m_table[offset + 8..offset + 8 + name_len], name[..]);
+
+            entries.push((name, extent, parent));
+            offset += 8 + name_len + name_len % 2;
+        }
+
+        let names = entries.iter().map(|(name, _, _)| String::from_utf8_lossy(name).into_owned()).collect::<Vec<_>>();
+        assert_eq!(names, [ "\0", "BOOT", "EMPTY", "MODULES" ]);
+        let parents = entries.iter().map(|(_, _, parent)| *parent).collect::<Vec<_>>();
+        assert_eq!(parents, [ 1, 1, 1, 2 ]);
+
+        // extents match the directory records
+        let root = entries[0].1;
+        assert_eq!(root, u32_both(pvd, 158));
+        assert_eq!(lookup(&image, root, b"BOOT").unwrap().0, entries[1].1);
+        assert_eq!(lookup(&image, entries[1].1, b"MODULES").unwrap().0, entries[3].1);
+
+        let (module, size) = lookup(&image, entries[3].1, b"K_A_LONG_MODULE_NAME.O;1").unwrap();
+        assert_eq!(&image[module as usize * SECTOR as usize..][..size as usize], b"module");
+    }
+
+    #[test]
+    fn boot_catalog() {
+        let image = sample_image("boot-catalog");
+        let root = u32_both(sector(&image, 16), 158);
+
+        let boot_record = sector(&image, 17);
+        assert_eq!(boot_record[0], 0);
+        assert_eq!(&boot_record[7..30], b"EL TORITO SPECIFICATION");
+        let catalog = sector(&image, u32_le(boot_record, 71));
+
+        // validation entry: 0x55aa, and words summing to 0
+        assert_eq!((catalog[0], catalog[30], catalog[31]), (1, 0x55, 0xaa));
+        let sum = (0..32).step_by(2).fold(0u16, |sum, i| sum.wrapping_add(u16_le(catalog, i)));
+        assert_eq!(sum, 0);
+
+        // initial entry: the BIOS image, with its boot info table
+        let (bios, bios_size) = lookup(&image, root, b"LIMINE_CD.BIN;1").unwrap();
+        assert_eq!(catalog[32], 0x88);
+        assert_eq!(u16_le(catalog, 38), 4);
+        assert_eq!(u32_le(catalog, 40), bios);
+        let bios_image = &image[bios as usize * SECTOR as usize..][..bios_size as usize];
+        assert_eq!((u32_le(bios_image, 8), u32_le(bios_image, 12), u32_le(bios_image, 16)), (16, bios, 5000));
+        assert!(bios_image[64..].iter().all(|byte| *byte == 0xfa));
+
+        // final section header for EFI, and its entry
+        let (efi, _) = lookup(&image, root, b"LIMINE_CD_EFI.BIN;1").unwrap();
+        assert_eq!((catalog[64], catalog[65], u16_le(catalog, 66)), (0x91, 0xef, 1));
+        assert_eq!(catalog[96], 0x88);
+        assert_eq!(u16_le(catalog, 102), 6);
+        assert_eq!(u32_le(catalog, 104), efi);
+
+        // and the MBR partition pointing to it
+        assert_eq!(image[446 + 4], 0xef);
+        assert_eq!(u32_le(&image, 446 + 8), efi * 4);
+        assert_eq!(u32_le(&image, 446 + 12), 6);
+        assert_eq!(&image[510..512], [ 0x55, 0xaa ]);
+    }
+
+    #[test]
+    fn rock_ridge_extension_is_announced() {
+        let image = sample_image("rock-ridge");
+        let root = u32_both(sector(&image, 16), 158);
+
+        // SP comes first in the system use area of the root's `.` record, then PX and CE
+        let offset = root as usize * SECTOR as usize;
+        let record = &image[offset..offset + image[offset] as usize];
+        let system_use = &record[34..];
+        assert_eq!(&system_use[..7], &[ b'S', b'P', 7, 1, 0xbe, 0xef, 0 ]);
+        let ce = &system_use[7 + 36..];
+        assert_eq!(&ce[..4], &[ b'C', b'E', 28, 1 ]);
+        let (lba, offset, len) = (u32_both(ce, 4), u32_both(ce, 12), u32_both(ce, 20));
+        assert_eq!(ce.len(), 28);
+
+        let er = &sector(&image, lba)[offset as usize..][..len as usize];
+        assert_eq!((&er[..2], er[2] as u32, er[3]), (&b"ER"[..], len, 1));
+        let (id_len, descriptor_len, source_len) = (er[4] as usize, er[5] as usize, er[6] as usize);
+        assert_eq!(er[7], 1);
+        assert_eq!(&er[8..8 + id_len], b"RRIP_1991A");
+        assert_eq!(8 + id_len + descriptor_len + source_len, len as usize);
+        assert!(er[8 + id_len..].starts_with(b"THE ROCK RIDGE INTERCHANGE PROTOCOL"));
+    }
+
+    #[test]
+    fn padding_never_goes_backwards() {
+        let dir = TempDir::new("padding");
+        let mut writer = Writer { inner: BufWriter::new(File::create(dir.join("image.iso")).unwrap()), position: 0 };
+
+        writer.write_all(&[0; 3000]).unwrap();
+        writer.pad_to(2).unwrap();
+        assert_eq!(writer.position, 2 * SECTOR);
+        let error = writer.pad_to(1).unwrap_err();
+        assert!(error.to_string().contains("already written"), "{}", error);
+    }
+
+    #[test]
+    fn files_larger_than_4_gib() {
+        let dir = TempDir::new("large");
+        create_dir_all(dir.join("root")).unwrap();
+        File::create(dir.join("root/large")).unwrap().set_len(u32::MAX as u64 + 1).unwrap();
+
+        let options = Options { volume_id: "THESEUS".into(), mtime: 0, bios_boot: None, efi_boot: None };
+        let result = write_iso(&dir.join("root"), &dir.join("large.iso"), &options);
+        assert!(result.unwrap_err().contains("larger than 4 GiB"));
+    }
+}
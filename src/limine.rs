@@ -0,0 +1,621 @@
+//! Provides the limine sources and pre-built binaries used by `add-bootloader`.
+
+use crate::log;
+use crate::oops;
+use crate::run;
+use crate::Config;
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::metadata;
+use std::fs::read;
+use std::fs::read_to_string;
+use std::fs::write;
+use std::fs::rename;
+use std::fs::remove_dir_all;
+use std::fs::remove_file;
+use std::io::copy;
+use std::io::BufReader;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+use flate2::read::GzDecoder;
+use sha2::Digest;
+use sha2::Sha256;
+use tar::Archive;
+
+/// Makes the limine directory available according to `add-bootloader.limine-source`
+/// and returns its path.
+pub fn provide(stage: &str, config: &Config) -> String {
+    let source = config.str("add-bootloader.limine-source");
+    let limine_tarball = config.str("add-bootloader.limine-tarball");
+    let limine_dir = config.str("add-bootloader.limine-dir");
+    let tarball_path = config.str("add-bootloader.tarball-path");
+    let extract_dir = config.str("add-bootloader.extract-dir");
+    let expected_subdir = config.str("add-bootloader.expected-subdir");
+    let downloader = config.str("add-bootloader.downloader");
+
+    let tarball_path = match source.as_str() {
+        // vendored sources are used as they are
+        "directory" => match metadata(&limine_dir) {
+            Ok(info) if info.is_dir() => return limine_dir,
+            _ => oops!(stage, "limine-dir {} isn't a directory", limine_dir),
+        },
+        "tarball" => limine_tarball.clone(),
+        "download" => tarball_path,
+        _ => oops!(stage, "limine-source must be \"download\", \"tarball\" or \"directory\""),
+    };
+
+    // the hash of the tarball which the extracted directory comes from
+    let stamp_path = format!("{}.sha256", extract_dir);
+    let stamp = read_to_string(&stamp_path).unwrap_or_default();
+    let stamp = stamp.trim();
+    let expected = config.str("add-bootloader.limine-sha256").to_lowercase();
+    let extracted = metadata(&expected_subdir).is_ok();
+
+    if extracted && !expected.is_empty() && stamp == expected {
+        return expected_subdir;
+    }
+
+    if source == "download" && metadata(&tarball_path).is_err() {
+        if !limine_tarball.starts_with("https://") {
+            oops!(stage, "limine-tarball must be an https URL; use limine-source = \"tarball\" for local files");
+        }
+
+        log!(stage, "fetching limine pre-built binaries");
+
+        let output_option = match downloader.as_str() {
+            "wget" => "-O",
+            "curl" => "-o",
+            _ => oops!(stage, "unsupported downloader: {}; must be wget or curl.", &downloader),
+        };
+
+        run(stage, &downloader, &[&[output_option, &tarball_path, &limine_tarball]]);
+    }
+
+    let actual = verify_checksum(stage, config, &tarball_path);
+    if extracted && stamp == actual {
+        return expected_subdir;
+    }
+
+    log!(stage, "extracting limine pre-built binaries");
+
+    // extract next to the final location, so that an interrupted
+    // extraction isn't mistaken for a complete one
+    let partial_dir = format!("{}.partial", extract_dir);
+    let _ = remove_file(&stamp_path);
+    let _ = remove_dir_all(&partial_dir);
+    let _ = remove_dir_all(&extract_dir);
+
+    if let Err(e) = extract(&tarball_path, &partial_dir) {
+        oops!(stage, "failed to extract {}: {}", tarball_path, e);
+    }
+    if let Err(e) = rename(&partial_dir, &extract_dir) {
+        oops!(stage, "couldn't move {} to {}: {}", partial_dir, extract_dir, e);
+    }
+
+    if metadata(&expected_subdir).is_err() {
+        oops!(stage, "{} doesn't contain {}; check limine-subdir", tarball_path, expected_subdir);
+    }
+
+    if let Err(e) = write(&stamp_path, &actual) {
+        oops!(stage, "couldn't write {}: {}", stamp_path, e);
+    }
+
+    expected_subdir
+}
+
+/// Checks the tarball against `add-bootloader.limine-sha256`, and returns its SHA-256.
+fn verify_checksum(stage: &str, config: &Config, tarball_path: &str) -> String {
+    let expected = config.str("add-bootloader.limine-sha256").to_lowercase();
+
+    let mut file = match File::open(tarball_path) {
+        Ok(file) => file,
+        Err(e) => oops!(stage, "couldn't open {}: {}", tarball_path, e),
+    };
+
+    let mut hasher = Sha256::new();
+    copy(&mut file, &mut hasher).unwrap();
+    let actual = hasher.finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    if expected.is_empty() {
+        log!(stage, "warning: limine-sha256 isn't set; {} has SHA-256 {}", tarball_path, actual);
+    } else if actual != expected {
+        oops!(stage, "checksum mismatch for {}: expected SHA-256 {}, got {}", tarball_path, expected, actual);
+    } else {
+        log!(stage, "{} matches limine-sha256", tarball_path);
+    }
+
+    actual
+}
+
+/// Unpacks a tar archive, which may be gzip- or zstd-compressed.
+fn extract(tarball_path: &str, output_dir: &str) -> std::io::Result<()> {
+    let mut reader = BufReader::new(File::open(tarball_path)?);
+
+    let reader: Box<dyn Read> = match reader.fill_buf()? {
+        [ 0x1f, 0x8b, .. ] => Box::new(GzDecoder::new(reader)),
+        [ 0x28, 0xb5, 0x2f, 0xfd, .. ] => Box::new(zstd::stream::Decoder::with_buffer(reader)?),
+        _ => Box::new(reader),
+    };
+
+    Archive::new(reader).unpack(output_dir)
+}
+
+/// Installs limine's BIOS stages into the image, according to `add-bootloader.limine-deploy`.
+pub fn deploy(stage: &str, config: &Config, limine_dir: &str, image: &str) {
+    let method = config.str("add-bootloader.limine-deploy");
+
+    match method.as_str() {
+        "build" => {
+            let limine_deploy = build_limine_deploy(stage, config, limine_dir);
+            log!(stage, "running limine-deploy on {}", image);
+            run(stage, &limine_deploy, &[&[image]]);
+        },
+        "native" => {
+            log!(stage, "deploying limine to {}", image);
+            let bootloader = read_bootloader_image(stage, limine_dir);
+            if let Err(e) = deploy_native(&bootloader, image) {
+                oops!(stage, "failed to deploy limine to {}: {}", image, e);
+            }
+        },
+        _ => oops!(stage, "limine-deploy must be \"build\" or \"native\""),
+    }
+}
+
+/// Builds `limine-deploy`, unless the cached build was made from the same sources:
+/// the same `add-bootloader.limine-commit`, directory and source files.
+///
+/// Returns the path of the cached binary.
+fn build_limine_deploy(stage: &str, config: &Config, limine_dir: &str) -> String {
+    let commit = config.str("add-bootloader.limine-commit");
+    let cache = config.str("add-bootloader.limine-deploy-cache");
+    let stamp = format!("{}.stamp", cache);
+
+    let mut hasher = Sha256::new();
+    for source in [ "limine-deploy.c", "limine-hdd.h", "Makefile" ] {
+        if let Ok(bytes) = read(format!("{}/{}", limine_dir, source)) {
+            hasher.update(source.as_bytes());
+            hasher.update(&bytes);
+        }
+    }
+    let sources = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    let directory = fs::canonicalize(limine_dir).map_or(limine_dir.to_string(), |path| path.display().to_string());
+    let built_from = format!("{}\n{}\n{}\n", commit, directory, sources);
+
+    let up_to_date = read_to_string(&stamp).is_ok_and(|built| built == built_from);
+    if up_to_date && metadata(&cache).is_ok() {
+        log!(stage, "reusing {}", cache);
+        return cache;
+    }
+
+    log!(stage, "building limine-deploy");
+
+    run(stage, "make", &[&["-C", limine_dir]]);
+
+    let built = format!("{}/limine-deploy", limine_dir);
+    if let Err(e) = fs::copy(&built, &cache) {
+        oops!(stage, "couldn't copy {} to {}: {}", built, cache, e);
+    }
+    if let Err(e) = write(&stamp, &built_from) {
+        oops!(stage, "couldn't write {}: {}", stamp, e);
+    }
+
+    cache
+}
+
+/// Reads `limine-hdd.bin`, or extracts it from the `limine-hdd.h` header
+/// which `limine-deploy` is built with.
+fn read_bootloader_image(stage: &str, limine_dir: &str) -> Vec<u8> {
+    let bin_path = format!("{}/limine-hdd.bin", limine_dir);
+    let header_path = format!("{}/limine-hdd.h", limine_dir);
+
+    if let Ok(bytes) = read(&bin_path) {
+        return bytes;
+    }
+
+    let header = match read_to_string(&header_path) {
+        Ok(header) => header,
+        Err(_) => oops!(stage, "{} contains neither limine-hdd.bin nor limine-hdd.h", limine_dir),
+    };
+
+    // the header is a C array: `... = { 0xeb, 0x3c, ... };`
+    let array = header.split_once('{').and_then(|(_, rest)| rest.split_once('}'));
+    let bytes = array.and_then(|(array, _)| {
+        array.split(',')
+            .map(str::trim)
+            .filter(|byte| !byte.is_empty())
+            .map(|byte| u8::from_str_radix(byte.trim_start_matches("0x"), 16).ok())
+            .collect::<Option<Vec<u8>>>()
+    });
+
+    match bytes {
+        Some(bytes) => bytes,
+        None => oops!(stage, "couldn't parse {}", header_path),
+    }
+}
+
+/// Offset in the boot sector of the sizes and locations of the two halves of stage 2
+const STAGE2_INFO: u64 = 0x1a4;
+/// Size of the GPT partition entry arrays which `limine-deploy` assumes, in logical blocks
+const GPT_ARRAY_BLOCKS: u64 = 32;
+/// Size of a GPT header, as `limine-deploy` reads and writes it
+const GPT_HEADER_SIZE: usize = 92;
+
+/// Does what `limine-deploy` does: stage 1 goes to the boot sector, around the
+/// disk timestamp and the partition table, and stage 2 is split in two halves.
+///
+/// With GPT, the halves go at the end of the primary and secondary partition entry arrays,
+/// which are shrunk accordingly. Otherwise, they go in the gap following the MBR.
+fn deploy_native(bootloader: &[u8], image: &str) -> Result<(), String> {
+    if bootloader.len() <= 512 {
+        return Err("the limine bootloader image is too small".into());
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(image).map_err(|e| e.to_string())?;
+    let io = |e: std::io::Error| e.to_string();
+
+    // the first half gets the odd sector; both sizes are recorded in whole sectors
+    let stage2 = &bootloader[512..];
+    let stage2_sectors = stage2.len().div_ceil(512);
+    let size_a = (stage2_sectors / 2) * 512 + (stage2_sectors % 2) * 512;
+    let size_b = (stage2_sectors / 2) * 512;
+    let (stage2_a, stage2_b) = stage2.split_at(size_a.min(stage2.len()));
+
+    let (location_a, location_b) = match find_gpt(&mut file).map_err(io)? {
+        Some(gpt) => make_room_in_gpt(&mut file, gpt, size_a, size_b)?,
+        None => {
+            let location_a = 512;
+            let location_b = location_a + size_a as u64;
+            let end = location_b + stage2_b.len() as u64;
+            check_mbr_gap(&mut file, end)?;
+            check_iso_system_area(&mut file, end)?;
+            (location_a, location_b)
+        },
+    };
+
+    // stage 1, preserving the disk timestamp (218..224) and the partition table (440..510)
+    let timestamp = read_at(&mut file, 218, 6).map_err(io)?;
+    let partition_table = read_at(&mut file, 440, 70).map_err(io)?;
+    write_at(&mut file, 0, &bootloader[0..512]).map_err(io)?;
+
+    write_at(&mut file, location_a, stage2_a).map_err(io)?;
+    write_at(&mut file, location_b, stage2_b).map_err(io)?;
+
+    let mut info = Vec::new();
+    info.extend_from_slice(&(size_a as u16).to_le_bytes());
+    info.extend_from_slice(&(size_b as u16).to_le_bytes());
+    info.extend_from_slice(&location_a.to_le_bytes());
+    info.extend_from_slice(&location_b.to_le_bytes());
+    write_at(&mut file, STAGE2_INFO, &info).map_err(io)?;
+
+    write_at(&mut file, 218, &timestamp).map_err(io)?;
+    write_at(&mut file, 440, &partition_table).map_err(io)?;
+
+    Ok(())
+}
+
+struct Gpt {
+    /// Logical block size
+    block: u64,
+    primary: Vec<u8>,
+    secondary: Vec<u8>,
+}
+
+fn find_gpt(file: &mut File) -> std::io::Result<Option<Gpt>> {
+    for block in [ 512, 4096 ] {
+        let primary = read_at(file, block, GPT_HEADER_SIZE)?;
+        if &primary[0..8] != b"EFI PART" {
+            continue;
+        }
+
+        let alternate_lba = u64_at(&primary, 32);
+        let secondary = read_at(file, alternate_lba * block, GPT_HEADER_SIZE)?;
+        if &secondary[0..8] != b"EFI PART" {
+            continue;
+        }
+
+        return Ok(Some(Gpt { block, primary, secondary }));
+    }
+
+    Ok(None)
+}
+
+/// Shrinks both partition entry arrays to make room for stage 2 at their end,
+/// taken to be 32 logical blocks after their start as `limine-deploy` does.
+///
+/// Returns the locations of the two halves.
+fn make_room_in_gpt(file: &mut File, gpt: Gpt, size_a: usize, size_b: usize) -> Result<(u64, u64), String> {
+    let io = |e: std::io::Error| e.to_string();
+    let Gpt { block, mut primary, mut secondary } = gpt;
+
+    let entry_count = u32_at(&primary, 80) as u64;
+    let entry_size = u32_at(&primary, 84) as u64;
+    let primary_array = u64_at(&primary, 72) * block;
+    let secondary_array = u64_at(&secondary, 72) * block;
+    if entry_size == 0 || entry_size > block {
+        return Err(format!("unsupported GPT partition entry size: {}", entry_size));
+    }
+
+    // entries are in use if they have a unique partition GUID
+    let entries = read_at(file, primary_array, (entry_count * entry_size) as usize).map_err(io)?;
+    let used = entries.chunks(entry_size as usize)
+        .rposition(|entry| entry[16..32].iter().any(|byte| *byte != 0))
+        .map_or(0, |last| last as u64 + 1);
+
+    let location = |array_start: u64, size: usize| {
+        match (array_start + GPT_ARRAY_BLOCKS * block).checked_sub(size as u64) {
+            Some(location) if location & !(block - 1) >= array_start => Ok(location & !(block - 1)),
+            _ => Err(String::from("stage 2 doesn't fit in the GPT partition entry arrays")),
+        }
+    };
+    let location_a = location(primary_array, size_a)?;
+    let location_b = location(secondary_array, size_b)?;
+
+    let new_count = (location_a - primary_array) / block * (block / entry_size);
+    if new_count < used {
+        return Err(format!("stage 2 would overwrite GPT partition entries ({} used, room for {})", used, new_count));
+    }
+
+    // clear what's left of both arrays past the used entries, then checksum the primary one
+    let unused = vec![0; ((new_count - used) * entry_size) as usize];
+    write_at(file, primary_array + used * entry_size, &unused).map_err(io)?;
+    write_at(file, secondary_array + used * entry_size, &unused).map_err(io)?;
+    let entries = read_at(file, primary_array, (new_count * entry_size) as usize).map_err(io)?;
+    let entries_crc = crc32fast::hash(&entries);
+
+    let secondary_lba = u64_at(&primary, 32);
+    for (header, lba) in [ (&mut primary, 1), (&mut secondary, secondary_lba) ] {
+        header[80..84].copy_from_slice(&(new_count as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        header[16..20].copy_from_slice(&[0; 4]);
+        let header_crc = crc32fast::hash(&header[..]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+        write_at(file, lba * block, header).map_err(io)?;
+    }
+
+    Ok((location_a, location_b))
+}
+
+/// Checks that no MBR partition starts before `end`, in bytes.
+fn check_mbr_gap(file: &mut File, end: u64) -> Result<(), String> {
+    let mbr = read_at(file, 0, 512).map_err(|e| e.to_string())?;
+
+    for partition in 0..4 {
+        let entry = &mbr[446 + partition * 16..][..16];
+        let kind = entry[4];
+        let start = u32_at(entry, 8) as u64 * 512;
+        if kind != 0 && start < end {
+            return Err(format!(
+                "MBR partition {} starts at byte {}, but stage 2 needs the first {} bytes of the image",
+                partition + 1, start, end,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that stage 2 fits in the system area of ISO 9660 images, before the volume descriptors.
+fn check_iso_system_area(file: &mut File, end: u64) -> Result<(), String> {
+    const SYSTEM_AREA: u64 = 16 * 2048;
+
+    let is_iso = match read_at(file, SYSTEM_AREA + 1, 5) {
+        Ok(magic) => magic == b"CD001",
+        Err(_) => false,
+    };
+
+    match is_iso && end > SYSTEM_AREA {
+        true => Err(format!("stage 2 needs the first {} bytes of the image, but the ISO 9660 system area is only {}", end, SYSTEM_AREA)),
+        false => Ok(()),
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn write_at(file: &mut File, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::disk_image::write_disk_image;
+    use crate::disk_image::Options;
+    use crate::disk_image::PartitionTable;
+    use crate::iso9660;
+    use crate::test_util::u16_le;
+    use crate::test_util::u32_le;
+    use crate::test_util::u64_le;
+    use crate::test_util::TempDir;
+
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /// A fake limine-hdd.bin: a recognizable stage 1, and a stage 2 of
+    /// 5 sectors once rounded up, split into halves of 3 and 2 sectors.
+    fn bootloader(stage2_len: usize) -> Vec<u8> {
+        let mut bootloader = (0..512).map(|i| i as u8 ^ 0x5a).collect::<Vec<_>>();
+        bootloader.extend((0..stage2_len).map(|i| (i % 251) as u8));
+        bootloader
+    }
+
+    /// Deploys `bootloader` to `image`, and returns the image before and after.
+    fn deploy(bootloader: &[u8], image: &Path) -> (Vec<u8>, Result<Vec<u8>, String>) {
+        let before = read(image).unwrap();
+        let result = deploy_native(bootloader, image.to_str().unwrap());
+        (before, result.map(|_| read(image).unwrap()))
+    }
+
+    /// Checks stage 1, the preserved parts of the MBR and the stage 2 halves, and returns their locations.
+    fn check_deployed(bootloader: &[u8], before: &[u8], after: &[u8]) -> (u64, u64) {
+        let stage2 = &bootloader[512..];
+
+        // stage 1, around the disk timestamp, the stage 2 information and the partition table
+        assert!(after[..218] == bootloader[..218]);
+        assert!(after[218..224] == before[218..224]);
+        assert!(after[224..STAGE2_INFO as usize] == bootloader[224..STAGE2_INFO as usize]);
+        assert!(after[440..510] == before[440..510]);
+        assert!(after[510..512] == bootloader[510..512]);
+
+        let info = STAGE2_INFO as usize;
+        let (size_a, size_b) = (u16_le(after, info) as usize, u16_le(after, info + 2) as usize);
+        let (location_a, location_b) = (u64_le(after, info + 4), u64_le(after, info + 12));
+        assert_eq!((size_a, size_b), (3 * 512, 2 * 512));
+
+        assert!(after[location_a as usize..][..size_a] == stage2[..size_a]);
+        assert!(after[location_b as usize..][..stage2.len() - size_a] == stage2[size_a..]);
+        (location_a, location_b)
+    }
+
+    fn disk_image(dir: &TempDir, partition_table: PartitionTable) -> PathBuf {
+        let kernel = dir.join("kernel");
+        write(&kernel, b"kernel").unwrap();
+        let image = dir.join("image");
+        let options = Options { partition_table, esp_size: 0, label: "THESEUS".into(), mtime: 0 };
+        write_disk_image(&image, &[ ("boot/kernel".into(), kernel) ], &options).unwrap();
+        image
+    }
+
+    #[test]
+    fn deploy_native_gpt() {
+        let dir = TempDir::new("deploy-gpt");
+        let image = disk_image(&dir, PartitionTable::Gpt);
+        let bootloader = bootloader(4 * 512 + 100);
+
+        let (before, after) = deploy(&bootloader, &image);
+        let after = after.unwrap();
+        let (location_a, location_b) = check_deployed(&bootloader, &before, &after);
+
+        // the halves end 32 blocks after the start of each entry array, which is shrunk to what's before them
+        let last_lba = after.len() as u64 / 512 - 1;
+        let secondary_array = last_lba - 32;
+        assert_eq!(location_a, (2 + 32) * 512 - 3 * 512);
+        assert_eq!(location_b, (secondary_array + 32) * 512 - 2 * 512);
+        let entry_count = 29 * 512 / 128;
+
+        for (lba, array) in [ (1, 2), (last_lba, secondary_array) ] {
+            let header = &after[lba as usize * 512..][..GPT_HEADER_SIZE];
+            assert_eq!(&header[0..8], b"EFI PART");
+            assert_eq!((u64_le(header, 72), u32_le(header, 80)), (array, entry_count));
+
+            let mut zeroed = header.to_vec();
+            zeroed[16..20].copy_from_slice(&[0; 4]);
+            assert_eq!(u32_le(header, 16), crc32fast::hash(&zeroed));
+            let entries = &after[array as usize * 512..][..entry_count as usize * 128];
+            assert_eq!(u32_le(header, 88), crc32fast::hash(entries));
+        }
+
+        // the ESP entry is kept
+        assert!(after[2 * 512..][..128] == before[2 * 512..][..128]);
+    }
+
+    #[test]
+    fn deploy_native_mbr() {
+        let dir = TempDir::new("deploy-mbr");
+        let image = disk_image(&dir, PartitionTable::Mbr);
+        let bootloader = bootloader(4 * 512 + 100);
+
+        let (before, after) = deploy(&bootloader, &image);
+        let after = after.unwrap();
+
+        // the halves follow the MBR, before the first partition
+        assert_eq!(check_deployed(&bootloader, &before, &after), (512, 512 + 3 * 512));
+        // stage 2 ends at 512 + its length, which is the length of the whole bootloader
+        assert!(after[bootloader.len()..] == before[bootloader.len()..]);
+    }
+
+    #[test]
+    fn deploy_native_iso() {
+        let dir = TempDir::new("deploy-iso");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("boot")).unwrap();
+        write(root.join("limine-cd.bin"), vec![0xfa; 5000]).unwrap();
+        write(root.join("limine-cd-efi.bin"), vec![0xef; 3000]).unwrap();
+        write(root.join("boot/kernel.bin"), b"kernel").unwrap();
+
+        let image = dir.join("image.iso");
+        let options = iso9660::Options {
+            volume_id: "THESEUS".into(),
+            mtime: 0,
+            bios_boot: Some("limine-cd.bin".into()),
+            efi_boot: Some("limine-cd-efi.bin".into()),
+        };
+        iso9660::write_iso(&root, &image, &options).unwrap();
+        let bootloader = bootloader(4 * 512 + 100);
+
+        let (before, after) = deploy(&bootloader, &image);
+        let after = after.unwrap();
+
+        // the halves go in the system area, which keeps the EFI partition entry; the volume is untouched
+        assert_eq!(check_deployed(&bootloader, &before, &after), (512, 512 + 3 * 512));
+        assert!(after[446..462] == before[446..462]);
+        assert!(after[16 * 2048..] == before[16 * 2048..]);
+
+        // a stage 2 too large for the system area is refused, leaving the image as it was
+        let (before, after) = deploy(&self::bootloader(16 * 2048), &image);
+        assert!(after.unwrap_err().contains("system area"));
+        assert!(read(&image).unwrap() == before);
+    }
+
+    /// Deploys with the `limine-deploy` built in `$LIMINE_DIR` and natively, and compares
+    /// the images: raw GPT and MBR disk images, and an ISO from the native writer.
+    #[test]
+    #[ignore = "needs LIMINE_DIR, with limine-deploy built"]
+    fn deploy_native_matches_limine_deploy() {
+        let limine_dir = std::env::var("LIMINE_DIR").expect("LIMINE_DIR isn't set");
+        let bootloader = read(format!("{}/limine-hdd.bin", limine_dir)).unwrap();
+
+        let dir = TempDir::new("deploy");
+        let kernel = dir.join("kernel.bin");
+        write(&kernel, vec![0x90; 100_000]).unwrap();
+
+        let reference = dir.join("reference.img");
+        let native = dir.join("native.img");
+        let compare = |name: &str| {
+            fs::copy(&reference, &native).unwrap();
+            let status = Command::new(format!("{}/limine-deploy", limine_dir)).arg(&reference).status().unwrap();
+            assert!(status.success());
+            deploy_native(&bootloader, native.to_str().unwrap()).unwrap();
+
+            assert!(read(&reference).unwrap() == read(&native).unwrap(), "{} images differ", name);
+        };
+
+        for partition_table in [ PartitionTable::Gpt, PartitionTable::Mbr ] {
+            let options = Options { partition_table, esp_size: 0, label: "THESEUS".into(), mtime: 0 };
+            write_disk_image(&reference, &[ ("boot/kernel.bin".into(), kernel.clone()) ], &options).unwrap();
+            compare("disk");
+        }
+
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("boot")).unwrap();
+        fs::copy(&kernel, root.join("boot/kernel.bin")).unwrap();
+        let options = iso9660::Options { volume_id: "THESEUS".into(), mtime: 0, bios_boot: None, efi_boot: None };
+        iso9660::write_iso(&root, &reference, &options).unwrap();
+        compare("ISO");
+    }
+}
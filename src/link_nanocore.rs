@@ -1,7 +1,19 @@
 use crate::log;
+use crate::oops;
 use crate::Config;
 use crate::run;
 use crate::list_dir;
+use crate::nanocore_layout;
+use crate::symbol_index;
+use crate::template::render;
+use crate::template::Scope;
+
+use std::fs::metadata;
+use std::fs::read_to_string;
+use std::fs::write;
+use std::time::SystemTime;
+
+use walkdir::WalkDir;
 
 
 pub fn process(config: &Config) {
@@ -14,35 +26,78 @@ pub fn process(config: &Config) {
     let static_lib = config.str("link-nanocore.static-lib-path");
     let asm_sources_dir = config.str("link-nanocore.asm-sources-dir");
     let nanocore_bin = config.str("nanocore-path");
-    let linker_script = config.str("link-nanocore.linker-script-path");
+    let linker_script = linker_script(stage, config);
+    let map_path = config.str("link-nanocore.map-path");
+
+    let assembler = assembler(stage, &arch);
+    let assembler_program = match config.str("link-nanocore.assembler").as_str() {
+        "" => assembler.program.to_string(),
+        program => program.to_string(),
+    };
+    let mut asm_flags = config.vec("link-nanocore.asm-flags");
+    if let Some(target_flag) = assembler.target_flag {
+        asm_flags.insert(0, format!("{}{}", target_flag, config.str("link-nanocore.asm-target")));
+    }
+    let asm_defines = config.vec("link-nanocore.asm-defines")
+        .iter()
+        .map(|define| format!("-D{}", define))
+        .collect::<Vec<_>>();
 
     log!(stage, "compiling assembly trampolines");
 
+    let mut sources = list_dir(stage, &asm_sources_dir)
+        .into_iter()
+        .filter(|(_, is_dir)| !is_dir)
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    // link objects in a stable order
+    sources.sort();
+
+    // everything but the input and output files
+    let arguments = assembler.flags.iter()
+        .map(|flag| flag.to_string())
+        .chain(asm_flags.iter().cloned())
+        .chain(asm_defines.iter().cloned())
+        .chain([ assembler.include_flag.to_string(), asm_sources_dir.clone() ])
+        .collect::<Vec<_>>();
+
+    // objects are out of date if the command line changed, or if a source or
+    // a file which sources may include (any other file, in any subdirectory) changed
+    let command_line = format!("{} {}", assembler_program, arguments.join(" "));
+    let stamp = format!("{}/asm_{}.stamp", &nanocore_dir, arch);
+    let includes_mtime = newest_include(&asm_sources_dir, assembler.extension);
+
     let mut asm_object_files = Vec::new();
+    let mut up_to_date = 0;
 
-    for (name, _is_dir) in list_dir(stage, &asm_sources_dir) {
-        let mut split = name.split(".asm");
-        let prefix = split.next();
-        if split.next().is_some() {
-            let entry = prefix.unwrap();
-            let input = format!("{}/{}.asm", asm_sources_dir, entry);
+    for name in &sources {
+        if let Some(entry) = name.strip_suffix(assembler.extension) {
+            let input = format!("{}/{}", asm_sources_dir, name);
             let output = format!("{}/asm_{}_{}.o", &nanocore_dir, entry, arch);
 
-            // todo: add cflags
-            run(stage, "nasm", &[&[
-                "-f",
-                "elf64",
-                "-i",
-                &asm_sources_dir,
-                &input,
-                "-o",
-                &output,
-            ]]);
+            if is_up_to_date(&output, &input, includes_mtime, &stamp, &command_line) {
+                up_to_date += 1;
+                asm_object_files.push(output);
+                continue;
+            }
+
+            run(stage, &assembler_program, &[
+                &arguments.iter().map(|arg| arg.as_str()).collect::<Vec<_>>(),
+                &[ &input, "-o", &output ],
+            ]);
 
             asm_object_files.push(output);
         }
     }
 
+    if let Err(e) = write(&stamp, &command_line) {
+        oops!(stage, "couldn't write {}: {}", stamp, e);
+    }
+
+    if up_to_date > 0 {
+        log!(stage, "{} of {} objects were up to date", up_to_date, asm_object_files.len());
+    }
+
     log!(stage, "linking nanocore");
 
     run(stage, &linker, &[
@@ -50,10 +105,211 @@ pub fn process(config: &Config) {
             "-n",
             "-T",
             &linker_script,
+            "-Map",
+            &map_path,
             "-o",
             &nanocore_bin,
         ],
         &asm_object_files.iter().map(|f| f.as_str()).collect::<Vec<_>>(),
         &[ &static_lib ],
     ]);
-}
\ No newline at end of file
+
+    log!(stage, "link map written to {}", map_path);
+
+    if config.bool("link-nanocore.validate-layout") {
+        nanocore_layout::validate(stage, config);
+    }
+
+    symbol_index::write(stage, config);
+}
+
+const BUILTIN_LINKER_SCRIPT: &str = include_str!("linker_higher_half.ld");
+
+/// Returns the path of the linker script: `linker-script-path`, or the
+/// script rendered from the `linker-script` template if it's set.
+///
+fn linker_script(stage: &str, config: &Config) -> String {
+    let template_path = config.str("link-nanocore.linker-script");
+
+    let template = match template_path.as_str() {
+        "" => {
+            // asm-sources-dir, where the script is by default, is specific to the arch
+            let path = config.str("link-nanocore.linker-script-path");
+            if metadata(&path).is_err() {
+                oops!(
+                    stage, "there is no linker script for {} at {}; add one, or set link-nanocore.linker-script",
+                    config.str("arch"), path,
+                );
+            }
+            return path;
+        },
+        "built-in" if config.str("arch") == "x86_64" => BUILTIN_LINKER_SCRIPT.to_string(),
+        "built-in" => oops!(stage, "there is no built-in linker script for {}", config.str("arch")),
+        path => match read_to_string(path) {
+            Ok(template) => template,
+            Err(e) => oops!(stage, "couldn't read {}: {}", path, e),
+        },
+    };
+
+    let output = config.str("link-nanocore.rendered-linker-script");
+    log!(stage, "generating {} from {}", output, template_path);
+    let script = render(stage, &template, &linker_script_scope(config), config);
+    if let Err(e) = write(&output, script) {
+        oops!(stage, "couldn't write {}: {}", output, e);
+    }
+
+    output
+}
+
+/// The variables of linker script templates.
+///
+/// They get the constants of `kernel_config::memory` that the builder
+/// was compiled with, which are also those `serialize-nanocore-syms` uses.
+fn linker_script_scope(config: &Config) -> Scope {
+    Scope::new()
+        .var("KERNEL_OFFSET", format!("{:#x}", kernel_config::memory::KERNEL_OFFSET))
+        .var("PAGE_SIZE", format!("{:#x}", kernel_config::memory::PAGE_SIZE))
+        .var("LOAD_ADDRESS", format!("{:#x}", config.int("link-nanocore.load-address")))
+}
+
+/// Returns whether the object assembled from `source` can be kept: it must have been
+/// assembled with `command_line`, which `stamp` records, and after `source` and any file
+/// it may include (whose newest modification time is `includes_mtime`) last changed.
+fn is_up_to_date(
+    object: &str,
+    source: &str,
+    includes_mtime: Option<SystemTime>,
+    stamp: &str,
+    command_line: &str,
+) -> bool {
+    let same_command_line = read_to_string(stamp).is_ok_and(|previous| previous == command_line);
+    let newer = |object: SystemTime| mtime(source).is_some_and(|source| object > source)
+        && includes_mtime.is_none_or(|includes| object > includes);
+
+    same_command_line && mtime(object).is_some_and(newer)
+}
+
+/// Returns when a file which sources may include last changed: any file of
+/// `asm_sources_dir` but the sources themselves, in any subdirectory.
+fn newest_include(asm_sources_dir: &str, extension: &str) -> Option<SystemTime> {
+    WalkDir::new(asm_sources_dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| entry.depth() > 1 || !entry.file_name().to_string_lossy().ends_with(extension))
+        .filter_map(|entry| entry.metadata().ok()?.modified().ok())
+        .max()
+}
+
+fn mtime(path: &str) -> Option<SystemTime> {
+    metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// How the assembly sources of an architecture are assembled.
+struct Assembler {
+    /// Sources are the files of asm-sources-dir with this extension
+    extension: &'static str,
+    /// Unless link-nanocore.assembler is set
+    program: &'static str,
+    flags: &'static [&'static str],
+    /// Flag taking link-nanocore.asm-target, for assemblers which handle several targets
+    target_flag: Option<&'static str>,
+    /// Flag adding asm-sources-dir to the include path
+    include_flag: &'static str,
+}
+
+fn assembler(stage: &str, arch: &str) -> Assembler {
+    match arch {
+        "x86_64" => Assembler {
+            extension: ".asm",
+            program: "nasm",
+            flags: &[ "-f", "elf64" ],
+            target_flag: None,
+            include_flag: "-i",
+        },
+        // preprocessed GNU assembly, which clang assembles for any target
+        "aarch64" => Assembler {
+            extension: ".S",
+            program: "clang",
+            flags: &[ "-c" ],
+            target_flag: Some("--target="),
+            include_flag: "-I",
+        },
+        _ => oops!(stage, "don't know how to assemble the nanocore for {}", arch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_CONFIG;
+    use crate::test_util::TempDir;
+    use std::fs::create_dir_all;
+    use std::fs::File;
+    use std::time::Duration;
+    use toml::Value;
+
+    /// Writes a file at `path`, last modified `seconds` after the epoch.
+    fn touch(path: &str, seconds: u64) {
+        let file = File::create(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)).unwrap();
+    }
+
+    #[test]
+    fn objects_are_up_to_date_until_something_changes() {
+        let dir = TempDir::new("link-nanocore");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let sources = path("asm");
+        create_dir_all(format!("{}/x86_64/boot", sources)).unwrap();
+        let source = format!("{}/start.asm", sources);
+        let object = path("asm_start_x86_64.o");
+        let stamp = path("asm_x86_64.stamp");
+
+        touch(&source, 100);
+        touch(&format!("{}/other.asm", sources), 400);
+        touch(&object, 200);
+        write(&stamp, "nasm -f elf64").unwrap();
+
+        // other sources aren't includes
+        assert_eq!(newest_include(&sources, ".asm"), None);
+        assert!(is_up_to_date(&object, &source, None, &stamp, "nasm -f elf64"));
+
+        // the command line changed, or was never stamped
+        assert!(!is_up_to_date(&object, &source, None, &stamp, "nasm -f elf64 -g"));
+        assert!(!is_up_to_date(&object, &source, None, &path("missing.stamp"), "nasm -f elf64"));
+
+        // files in subdirectories are includes, whatever their extension
+        touch(&format!("{}/x86_64/boot/defines.asm", sources), 150);
+        touch(&format!("{}/constants.inc", sources), 120);
+        let includes = newest_include(&sources, ".asm");
+        assert_eq!(includes, Some(SystemTime::UNIX_EPOCH + Duration::from_secs(150)));
+        assert!(is_up_to_date(&object, &source, includes, &stamp, "nasm -f elf64"));
+
+        // an include changed after the object was assembled
+        touch(&format!("{}/x86_64/boot/defines.asm", sources), 300);
+        let includes = newest_include(&sources, ".asm");
+        assert!(!is_up_to_date(&object, &source, includes, &stamp, "nasm -f elf64"));
+
+        // the source changed, or the object is missing
+        touch(&source, 250);
+        assert!(!is_up_to_date(&object, &source, None, &stamp, "nasm -f elf64"));
+        assert!(!is_up_to_date(&path("missing.o"), &source, None, &stamp, "nasm -f elf64"));
+    }
+
+    #[test]
+    fn builtin_linker_script_renders_every_tag() {
+        let config = Config::from(DEFAULT_CONFIG.parse::<Value>().unwrap());
+        let script = render("test", BUILTIN_LINKER_SCRIPT, &linker_script_scope(&config), &config);
+
+        // the script's own braces only surround sections and blocks, never a lone name
+        let leftover = script.match_indices('{').find(|&(i, _)| {
+            let rest = &script[i + 1..];
+            rest.find('}').is_some_and(|end| !rest[..end].contains(char::is_whitespace))
+        });
+        assert!(leftover.is_none(), "template tag left at {:?}:\n{}", leftover, script);
+
+        assert!(script.contains(&format!("KERNEL_OFFSET = {:#x};", kernel_config::memory::KERNEL_OFFSET)));
+        assert!(script.contains(". = 0x100000;"));
+    }
+}
@@ -20,6 +20,8 @@ use toml::Value;
 use pico_args::Arguments;
 
 mod discover;
+mod crate_graph;
+mod metadata;
 mod directories;
 mod gen_mk_config;
 mod build_cells;
@@ -28,9 +30,25 @@ mod serialize_nanocore_syms;
 mod relink_rlibs;
 mod copy_crate_objects;
 mod relink_objects;
+mod verify_symbols;
 mod strip_objects;
 mod add_bootloader;
 mod run_qemu;
+mod clean;
+mod size_report;
+mod verify_reproducible;
+mod elf;
+mod compression;
+mod template;
+mod limine;
+mod iso9660;
+mod fat32;
+mod disk_image;
+mod nanocore_layout;
+mod symbol_index;
+mod symbolize;
+#[cfg(test)]
+mod test_util;
 
 pub const DEFAULT_CONFIG: &'static str = include_str!("defaults.toml");
 
@@ -44,11 +62,23 @@ const STAGES: &'static [fn(config: &Config)] = &[
     relink_rlibs::process,
     copy_crate_objects::process,
     relink_objects::process,
+    verify_symbols::process,
     strip_objects::process,
     add_bootloader::process,
     run_qemu::process,
 ];
 
+type NamedCommand = (&'static str, fn(config: &Config));
+
+/// Operations which can be selected with `-s` but
+/// are never part of a stage range such as `..`
+const COMMANDS: &[NamedCommand] = &[
+    ("clean", clean::process),
+    ("size-report", size_report::process),
+    ("verify-reproducible", verify_reproducible::process),
+    ("symbolize", symbolize::process),
+];
+
 fn parse_stage(name: &str, last: bool) -> usize {
     match name {
         "" if !last               => 0,
@@ -62,11 +92,12 @@ fn parse_stage(name: &str, last: bool) -> usize {
         "relink-rlibs"            => 6,
         "copy-crate-objects"      => 7,
         "relink-objects"          => 8,
-        "strip-objects"           => 9,
-        "add-bootloader"          => 10,
-        "run-qemu"                => 11,
+        "verify-symbols"          => 9,
+        "strip-objects"           => 10,
+        "add-bootloader"          => 11,
+        "run-qemu"                => 12,
 
-        "" if last                => 11,
+        "" if last                => 12,
         _ => oops!("main", "unknown stage \"{}\"", name),
     }
 }
@@ -126,10 +157,16 @@ fn main() {
         };
 
         apply_overrides(&mut value, args.finish());
+        derive_options(&mut value);
 
         let config = Config::from(value);
 
         for group in groups.split(",") {
+            if let Some((_, command)) = COMMANDS.iter().find(|(name, _)| *name == group) {
+                command(&config);
+                continue;
+            }
+
             let range = if group.contains("..") {
                 let mut stages = group.split("..");
                 let first = parse_stage(&stages.next().unwrap(), false);
@@ -160,9 +197,21 @@ impl Config {
         opt_str(self.as_ref(), key)
     }
 
+    fn int(&self, key: &str) -> i64 {
+        opt_int(self.as_ref(), key)
+    }
+
     fn vec(&self, key: &str) -> Vec<String> {
         opt_str_vec(self.as_ref(), key)
     }
+
+    /// Returns a copy of this configuration in which `key` is set to `value`.
+    fn with(&self, key: &str, value: Value) -> Config {
+        let mut inner = self.inner.clone();
+        let (table, key) = get_config(&mut inner, key.split('.').map(String::from).collect());
+        table.as_table_mut().unwrap().insert(key, value);
+        Config::from(inner)
+    }
 }
 
 impl From<Value> for Config {
@@ -252,6 +301,17 @@ fn apply_overrides(config: &mut Value, override_args: Vec<OsString>) {
     }
 }
 
+/// Sets options whose default value can't be expressed using imports,
+/// unless they were explicitly set.
+fn derive_options(config: &mut Value) {
+    if config.get("profile-dir").is_none() {
+        let build_mode = opt_str(config, "build-mode");
+        let profile_dir = build_cells::profile_dir(&build_mode).to_string();
+        let config = config.as_table_mut().unwrap();
+        config.insert("profile-dir".to_string(), Value::String(profile_dir));
+    }
+}
+
 #[macro_export]
 macro_rules! log {
     ($log_stage:expr, $($arg:tt)*) => {{
@@ -385,6 +445,15 @@ pub fn opt_bool(config: &Value, key: &str) -> bool {
     }
 }
 
+pub fn opt_int(config: &Value, key: &str) -> i64 {
+    if let Value::Integer(integer) = opt(config, key) {
+        integer
+    } else {
+        println!("wrong type: {} must be an integer!", key);
+        crate::die();
+    }
+}
+
 pub fn opt_str(config: &Value, key: &str) -> String {
     if let Value::String(mut string) = opt(config, key) {
         resolve_imports(config, &mut string);
@@ -0,0 +1,84 @@
+use crate::oops;
+use crate::Config;
+
+use std::process::Command;
+use std::path::PathBuf;
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+/// The subset of `cargo metadata`'s output which the builder uses.
+#[derive(Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+}
+
+#[derive(Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub manifest_path: PathBuf,
+    pub targets: Vec<Target>,
+    pub features: BTreeMap<String, Vec<String>>,
+    pub dependencies: Vec<Dependency>,
+}
+
+#[derive(Deserialize)]
+pub struct Dependency {
+    pub name: String,
+    pub rename: Option<String>,
+    pub optional: bool,
+}
+
+#[derive(Deserialize)]
+pub struct Target {
+    pub name: String,
+    pub kind: Vec<String>,
+}
+
+impl Package {
+    /// Checks whether `feature` can be enabled on this package,
+    /// which is also the case for the implicit features of optional dependencies.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.contains_key(feature) || self.dependencies.iter().any(|dep| {
+            dep.optional && dep.rename.as_ref().unwrap_or(&dep.name) == feature
+        })
+    }
+
+    /// The name of the package's library, as it appears in artifact file names.
+    pub fn crate_name(&self) -> String {
+        let lib = self.targets.iter().find(|target| {
+            target.kind.iter().any(|kind| kind == "lib" || kind == "rlib" || kind == "staticlib")
+        });
+        match lib {
+            Some(target) => target.name.replace('-', "_"),
+            None => self.name.replace('-', "_"),
+        }
+    }
+}
+
+/// Runs `cargo metadata` on `build-cells.manifest-path`, without resolving dependencies.
+///
+/// Only workspace members are listed in the result.
+pub fn cargo_metadata(stage: &str, config: &Config) -> Metadata {
+    let cargo = config.str("build-cells.cargo");
+    let toolchain = config.str("build-cells.toolchain");
+    let manifest_path = config.str("build-cells.manifest-path");
+
+    let result = Command::new(&cargo)
+        .arg(format!("+{}", &toolchain))
+        .arg("metadata")
+        .arg("--format-version=1")
+        .arg("--no-deps")
+        .arg(format!("--manifest-path={}", &manifest_path))
+        .output();
+
+    let stdout = match result {
+        Ok(output) if output.status.success() => output.stdout,
+        _ => oops!(stage, "{} metadata invocation failed", &cargo),
+    };
+
+    match serde_json::from_slice(&stdout) {
+        Ok(metadata) => metadata,
+        Err(e) => oops!(stage, "failed to parse cargo metadata: {}", e),
+    }
+}
@@ -0,0 +1,261 @@
+//! Checks the layout of the linked nanocore against what the rest of the
+//! build (`serialize-nanocore-syms`) and the kernel's loader expect.
+
+use crate::log;
+use crate::oops;
+use crate::Config;
+use crate::elf;
+use crate::elf::Section;
+
+use std::fs::read;
+
+use kernel_config::memory::KERNEL_OFFSET;
+use kernel_config::memory::PAGE_SIZE;
+
+pub fn validate(stage: &str, config: &Config) {
+    let nanocore_bin = config.str("nanocore-path");
+    let arch = config.str("arch");
+
+    log!(stage, "validating the layout of {}", nanocore_bin);
+
+    let bytes = match read(&nanocore_bin) {
+        Ok(bytes) => bytes,
+        Err(e) => oops!(stage, "couldn't read {}: {}", nanocore_bin, e),
+    };
+
+    let layout = elf::sections(&bytes).and_then(|sections| Ok((sections, elf::segments(&bytes)?)));
+    let (sections, segments) = match layout {
+        Ok(layout) => layout,
+        Err(e) => oops!(stage, "couldn't parse {}: {}", nanocore_bin, e),
+    };
+
+    let problems = problems(&arch, &sections, &segments);
+
+    for problem in &problems {
+        log!(stage, "{}: {}", nanocore_bin, problem);
+    }
+
+    if !problems.is_empty() {
+        oops!(
+            stage, "the nanocore's layout has {} problems; check the linker script ({})",
+            problems.len(), linker_script_setting(config),
+        );
+    }
+}
+
+fn linker_script_setting(config: &Config) -> String {
+    match config.str("link-nanocore.linker-script").as_str() {
+        "" => format!("link-nanocore.linker-script-path = {:?}", config.str("link-nanocore.linker-script-path")),
+        template => format!("link-nanocore.linker-script = {:?}", template),
+    }
+}
+
+/// Lists everything about the layout which would break loading the nanocore's symbols.
+fn problems(arch: &str, sections: &[Section], segments: &[elf::Segment]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let find = |name: &str| sections.iter().position(|section| section.name == name);
+
+    let mut required = vec![ ".text", ".rodata", ".data", ".bss" ];
+    if arch == "x86_64" {
+        required.insert(0, ".init");
+    }
+
+    for name in &required {
+        if find(name).is_none() {
+            problems.push(format!("the {} section is missing", name));
+        }
+    }
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    let section = |name: &str| &sections[find(name).unwrap()];
+    let text = section(".text");
+    let rodata = section(".rodata");
+    let data = section(".data");
+    let bss = section(".bss");
+
+    // sections are parsed in the order of their headers
+    let mut header_order = vec![ (".rodata", ".eh_frame"), (".rodata", ".gcc_except_table") ];
+    if arch == "x86_64" {
+        header_order.push((".init", ".text"));
+    }
+    for (first, second) in header_order {
+        if let (Some(first_index), Some(second_index)) = (find(first), find(second)) {
+            if second_index < first_index {
+                problems.push(format!("the {} section header must come before the {} one", first, second));
+            }
+        }
+    }
+
+    // .text, .rodata and .data are mapped separately, with their own permissions
+    for section in [ text, rodata, data ] {
+        if section.addr % PAGE_SIZE as u64 != 0 {
+            problems.push(format!("{} at {:#x} isn't aligned to a page ({:#x} bytes)", section.name, section.addr, PAGE_SIZE));
+        }
+    }
+
+    if !(text.addr < rodata.addr && rodata.addr < data.addr) {
+        problems.push(format!(
+            ".text ({:#x}), .rodata ({:#x}) and .data ({:#x}) must be in this order in memory",
+            text.addr, rodata.addr, data.addr,
+        ));
+    }
+
+    // these are loaded as parts of the .rodata pages
+    for name in [ ".eh_frame", ".gcc_except_table", ".tdata" ] {
+        if let Some(index) = find(name) {
+            let section = &sections[index];
+            if section.addr < rodata.addr || section.addr.saturating_add(section.size) > data.addr {
+                problems.push(format!(
+                    "{} at {:#x} must be between the start of .rodata ({:#x}) and .data ({:#x})",
+                    name, section.addr, rodata.addr, data.addr,
+                ));
+            }
+        }
+    }
+
+    // and .bss is part of the .data pages
+    if bss.addr < data.addr {
+        problems.push(format!(".bss at {:#x} must come after .data ({:#x})", bss.addr, data.addr));
+    }
+
+    if arch == "x86_64" {
+        let init = section(".init");
+
+        // the bootloader loads .init where it's linked, and the rest in the higher half
+        if init.addr >= KERNEL_OFFSET as u64 {
+            problems.push(format!(".init at {:#x} must be below KERNEL_OFFSET ({:#x})", init.addr, KERNEL_OFFSET));
+        }
+
+        let higher_half = sections.iter()
+            .filter(|section| section.flags & elf::SHF_ALLOC != 0)
+            .filter(|section| section.name != ".init" && section.name != ".tbss");
+
+        for section in higher_half {
+            if section.addr < (KERNEL_OFFSET as u64).saturating_add(init.addr) {
+                problems.push(format!(
+                    "{} at {:#x} must be above KERNEL_OFFSET ({:#x}) + the start of .init ({:#x})",
+                    section.name, section.addr, KERNEL_OFFSET, init.addr,
+                ));
+                continue;
+            }
+
+            let segment = segments.iter().find(|segment| {
+                segment.ty == elf::PT_LOAD && segment.vaddr <= section.addr && section.addr < segment.vaddr.saturating_add(segment.memsz.max(1))
+            });
+            if let Some(segment) = segment {
+                let load_address = segment.paddr + (section.addr - segment.vaddr);
+                if load_address != section.addr - KERNEL_OFFSET as u64 {
+                    problems.push(format!(
+                        "{} is loaded at {:#x}, but linked at {:#x}; the difference must be KERNEL_OFFSET ({:#x})",
+                        section.name, load_address, section.addr, KERNEL_OFFSET,
+                    ));
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elf::Segment;
+
+    const HIGHER_HALF: u64 = KERNEL_OFFSET as u64;
+
+    fn section(name: &str, addr: u64, size: u64) -> Section {
+        Section { name: name.to_string(), ty: 1, flags: elf::SHF_ALLOC, addr, size }
+    }
+
+    fn load(vaddr: u64, paddr: u64, memsz: u64) -> Segment {
+        Segment { ty: elf::PT_LOAD, vaddr, paddr, memsz }
+    }
+
+    /// The layout of the built-in linker script: .init at 1 MiB, the rest in the higher half.
+    fn layout() -> (Vec<Section>, Vec<Segment>) {
+        let sections = vec![
+            section(".init", 0x100000, 0x1000),
+            section(".text", HIGHER_HALF + 0x101000, 0x8000),
+            section(".rodata", HIGHER_HALF + 0x109000, 0x2000),
+            section(".eh_frame", HIGHER_HALF + 0x10b000, 0x800),
+            section(".gcc_except_table", HIGHER_HALF + 0x10b800, 0x100),
+            section(".data", HIGHER_HALF + 0x10c000, 0x1000),
+            section(".bss", HIGHER_HALF + 0x10d000, 0x4000),
+        ];
+        let segments = vec![
+            load(0x100000, 0x100000, 0x1000),
+            load(HIGHER_HALF + 0x101000, 0x101000, 0x10000),
+        ];
+        (sections, segments)
+    }
+
+    fn problems_with(change: impl FnOnce(&mut Vec<Section>, &mut Vec<Segment>)) -> Vec<String> {
+        let (mut sections, mut segments) = layout();
+        change(&mut sections, &mut segments);
+        problems("x86_64", &sections, &segments)
+    }
+
+    fn assert_problem(problems: &[String], expected: &str) {
+        assert!(problems.iter().any(|problem| problem.contains(expected)), "{:?} doesn't mention {:?}", problems, expected);
+    }
+
+    #[test]
+    fn valid_layout() {
+        let (sections, segments) = layout();
+        assert_eq!(problems("x86_64", &sections, &segments), Vec::<String>::new());
+
+        // aarch64 has no .init, and isn't checked against KERNEL_OFFSET
+        let sections: Vec<Section> = sections.into_iter().filter(|section| section.name != ".init").collect();
+        assert_eq!(problems("aarch64", &sections, &[]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn missing_sections() {
+        let problems = problems_with(|sections, _| sections.retain(|section| section.name != ".rodata"));
+        assert_eq!(problems, vec![ "the .rodata section is missing".to_string() ]);
+    }
+
+    #[test]
+    fn order() {
+        let problems = problems_with(|sections, _| sections[1].addr = HIGHER_HALF + 0x110000);
+        assert_problem(&problems, "must be in this order in memory");
+
+        let problems = problems_with(|sections, _| sections.swap(2, 3));
+        assert_problem(&problems, "the .rodata section header must come before the .eh_frame one");
+    }
+
+    #[test]
+    fn alignment() {
+        let problems = problems_with(|sections, _| sections[5].addr += 0x10);
+        assert_problem(&problems, ".data at 0xffffffff8010c010 isn't aligned to a page");
+    }
+
+    #[test]
+    fn rodata_and_data_containment() {
+        let problems = problems_with(|sections, _| sections[4].size = 0x1000);
+        assert_problem(&problems, ".gcc_except_table at 0xffffffff8010b800 must be between the start of .rodata");
+
+        let problems = problems_with(|sections, _| sections[6].addr = HIGHER_HALF + 0x108000);
+        assert_problem(&problems, ".bss at 0xffffffff80108000 must come after .data");
+    }
+
+    #[test]
+    fn kernel_offset() {
+        let problems = problems_with(|sections, _| sections[0].addr = HIGHER_HALF);
+        assert_problem(&problems, ".init at 0xffffffff80000000 must be below KERNEL_OFFSET");
+
+        let problems = problems_with(|sections, _| sections[1].addr = 0x101000);
+        assert_problem(&problems, ".text at 0x101000 must be above KERNEL_OFFSET");
+    }
+
+    #[test]
+    fn load_address() {
+        let problems = problems_with(|_, segments| segments[1].paddr = HIGHER_HALF + 0x101000);
+        assert_problem(&problems, ".text is loaded at 0xffffffff80101000, but linked at 0xffffffff80101000");
+        assert_problem(&problems, ".bss is loaded at");
+    }
+}
@@ -1,83 +1,28 @@
 use crate::log;
 use crate::oops;
 use crate::Config;
-use crate::check_result;
+use crate::symbol_index;
 
-use std::process::Command;
 use std::fs::write;
 
 use bincode::serde::encode_to_vec;
 use bincode::config::standard;
-use rustc_demangle::demangle;
 
 pub fn process(config: &Config) {
     let stage = "serialize-nanocore-syms";
 
     let output_path = config.str("serialize-nanocore-syms.output-path");
-    let readelf = config.str("serialize-nanocore-syms.readelf");
+    let arch = config.str("arch");
 
-    let nanocore_bin = config.str("nanocore-path");
-
-    log!(stage, "extracting symbol information");
-
-    let result = Command::new(readelf)
-        .arg("-W")
-        .arg("-S")
-        .arg("-s")
-        .arg(&nanocore_bin)
-        .output();
-
-    let readelf_output = match result {
-        Ok(output) => {
-            check_result(stage, Ok(output.status), "readelf");
-            String::from_utf8(output.stdout).unwrap()
-        },
-        _ => oops!(stage, "readelf"),
-    };
-
-    log!(stage, "filtering symbols");
-
-    let mut readelf_lines = readelf_output.lines();
-    let mut filtered = String::new();
-
-    while let Some(line) = readelf_lines.next() {
-        // copy lines into filtered, until we find the "Symbol table" line
-        if !line.starts_with("Symbol table") {
-            filtered.push_str(line); 
-            filtered.push_str("\n");
-            continue;
-        } else {
-            // we found the "Symbol table" line
-            filtered.push_str(line); // push that "Symbol table" line
-            filtered.push_str("\n");
-            filtered.push_str(readelf_lines.next().unwrap()); // and the next one ("   Num:    Value ...")
-            filtered.push_str("\n");
-            break; // we're at the first symbol table entry, continue onto the next part
-        }
-    }
-
-    // parse each symbol table entry and demangle the names
-    for line in readelf_lines {
-        // println!("line: {}", line);
-        // we need to find the mangled symbol in each symtab entry, which always starts with "_ZN"
-        if let Some(index) = line.find("_ZN") {
-            let (first_half, name_mangled) = line.split_at(index);
-            let demangled = demangle(name_mangled).to_string();
-            filtered.push_str(first_half); // no newline after this, since it's just a split line
-            filtered.push_str(&demangled);
-            filtered.push_str("\n");
-        }
-        // if we cannot find "_ZN", then there wasn't a mangled symbol (it might've been no_mangle)
-        else {
-            // so just preserve the line as is
-            filtered.push_str(line);
-            filtered.push_str("\n");
-        }
-    }
+    // the demangled output of readelf, written when linking
+    let filtered = symbol_index::load(stage, config);
 
     log!(stage, "serializing symbols");
 
-    let crate_items = parse_nanocore_symbol_file(filtered).unwrap();
+    let crate_items = match parse_nanocore_symbol_file(filtered, &arch) {
+        Ok(crate_items) => crate_items,
+        Err(e) => oops!(stage, "{} (is the nanocore's layout valid? see link-nanocore.validate-layout)", e),
+    };
 
     let serialized_crate = SerializedCrate {
         crate_name: "nano_core".to_string(),
@@ -109,7 +54,16 @@ use std::collections::{BTreeMap, BTreeSet};
 ///
 /// Basically, this parses the section list for offsets, size, and flag data,
 /// and parses the symbol table to populate the list of sections.
-fn parse_nanocore_symbol_file(symbol_str: String) -> Result<ParsedCrateItems, &'static str> {
+///
+/// Section headers are interpreted according to `arch`, the architecture of the nanocore.
+fn parse_nanocore_symbol_file(symbol_str: String, arch: &str) -> Result<ParsedCrateItems, &'static str> {
+    // only x86_64 has a dedicated section type for unwinding information
+    let eh_frame_type = match arch {
+        "x86_64" => "X86_64_UNWIND",
+        "aarch64" => "PROGBITS",
+        _ => return Err("parse_nanocore_symbol_file(): unsupported architecture"),
+    };
+
     // We don't care about the .init sections shndx.
     let mut init_vaddr: Option<usize> = None;
     let mut text: Option<(Shndx, usize)> = None;
@@ -175,9 +129,16 @@ fn parse_nanocore_symbol_file(symbol_str: String) -> Result<ParsedCrateItems, &'
         if line.contains(".init") && line.contains("PROGBITS") {
             init_vaddr = parse_section(line).map(|(_, vaddr, _)| vaddr);  
         } else if line.contains(".text ") && line.contains("PROGBITS") {
-            text = parse_section(line).map(|(shndx, _, _)| {
-                (shndx, kernel_config::memory::KERNEL_OFFSET + init_vaddr.expect(".text parsed before .init"))
-            });
+            text = match (arch, parse_section(line)) {
+                // .text is mapped along with .init, which the bootloader loads at
+                // its physical address, so the mapping starts at .init's higher-half address
+                ("x86_64", Some((shndx, _, _))) => {
+                    let init_vaddr = init_vaddr
+                        .ok_or("parse_nanocore_symbol_file(): the .text section header comes before the .init one")?;
+                    Some((shndx, kernel_config::memory::KERNEL_OFFSET + init_vaddr))
+                },
+                (_, section) => section.map(|(shndx, vaddr, _)| (shndx, vaddr)),
+            };
         } else if line.contains(".rodata ") && line.contains("PROGBITS") {
             rodata = parse_section(line).map(|(shndx, vaddr, _)| (shndx, vaddr));
         } else if line.contains(".tdata ") && line.contains("PROGBITS") {
@@ -188,7 +149,7 @@ fn parse_nanocore_symbol_file(symbol_str: String) -> Result<ParsedCrateItems, &'
             data = parse_section(line).map(|(shndx, vaddr, _)| (shndx, vaddr));
         } else if line.contains(".bss ") && line.contains("NOBITS") {
             bss = parse_section(line).map(|(shndx, ..)| shndx);
-        } else if line.contains(".eh_frame ") && line.contains("X86_64_UNWIND") {
+        } else if line.contains(".eh_frame ") && line.contains(eh_frame_type) {
             let (_, virtual_address, size) = parse_section(line)
                 .ok_or("Failed to parse the .eh_frame section header's address and size")?;
 
@@ -200,7 +161,8 @@ fn parse_nanocore_symbol_file(symbol_str: String) -> Result<ParsedCrateItems, &'
                     ty: SectionType::EhFrame,
                     global: false, // .eh_frame is not global
                     virtual_address,
-                    offset: virtual_address - rodata.expect(".eh_frame parsed before .rodata").1,
+                    offset: virtual_address - rodata
+                        .ok_or("parse_nanocore_symbol_file(): the .eh_frame section header comes before the .rodata one")?.1,
                     size,
                 },
             );
@@ -218,7 +180,8 @@ fn parse_nanocore_symbol_file(symbol_str: String) -> Result<ParsedCrateItems, &'
                     ty: SectionType::GccExceptTable,
                     global: false, // .gcc_except_table is not global
                     virtual_address,
-                    offset: virtual_address - rodata.expect(".gcc_except_table parsed before .rodata").1,
+                    offset: virtual_address - rodata
+                        .ok_or("parse_nanocore_symbol_file(): the .gcc_except_table section header comes before the .rodata one")?.1,
                     size,
                 },
             );
@@ -516,3 +479,129 @@ fn add_new_section(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use kernel_config::memory::KERNEL_OFFSET;
+
+    /// `readelf -W -S -s` of an x86_64 nanocore linked with the built-in linker script, abridged.
+    const X86_64_SYMBOLS: &str = "\
+There are 11 section headers, starting at offset 0xf000:
+
+Section Headers:
+  [Nr] Name              Type            Address          Off    Size   ES Flg Lk Inf Al
+  [ 0]                   NULL            0000000000000000 000000 000000 00      0   0  0
+  [ 1] .init             PROGBITS        0000000000100000 001000 000100 00  AX  0   0 4096
+  [ 2] .text             PROGBITS        ffffffff80101000 002000 008000 00  AX  0   0 4096
+  [ 3] .rodata           PROGBITS        ffffffff80109000 00a000 002000 00   A  0   0 4096
+  [ 4] .eh_frame         X86_64_UNWIND   ffffffff8010b000 00c000 000800 00   A  0   0  8
+  [ 5] .gcc_except_table PROGBITS        ffffffff8010b800 00c800 000100 00   A  0   0  4
+  [ 6] .tdata            PROGBITS        ffffffff8010b900 00c900 000010 00 WAT  0   0  8
+  [ 7] .tbss             NOBITS          ffffffff8010b910 00c910 000020 00 WAT  0   0  8
+  [ 8] .data             PROGBITS        ffffffff8010c000 00d000 001000 00  WA  0   0 4096
+  [ 9] .bss              NOBITS          ffffffff8010d000 00e000 004000 00  WA  0   0 4096
+  [10] .symtab           SYMTAB          0000000000000000 00e000 000100 18     11   5  8
+
+Symbol table '.symtab' contains 8 entries:
+   Num:    Value          Size Type    Bind   Vis      Ndx Name
+     0: 0000000000000000     0 NOTYPE  LOCAL  DEFAULT  UND 
+     1: 0000000000100000     0 NOTYPE  GLOBAL DEFAULT    1 start
+     2: ffffffff80101040   128 FUNC    GLOBAL DEFAULT    2 nano_core::nano_core_start::h0123456789abcdef
+     3: ffffffff80109010 0x186a0 OBJECT LOCAL  DEFAULT    3 core::fmt::TABLE::h89abcdef01234567
+     4: 0000000000000008     8 TLS     GLOBAL DEFAULT    6 CURRENT_TASK
+     5: ffffffff8010c100    16 OBJECT  WEAK   DEFAULT    8 logger::LOGGER
+     6: ffffffff8010d200    64 OBJECT  LOCAL  DEFAULT    9 memory::FRAMES
+     7: 0000000000000200     0 NOTYPE  LOCAL  DEFAULT  ABS KERNEL_STACK_PAGES
+";
+
+    /// The same for aarch64, which has no `.init` and a PROGBITS `.eh_frame`.
+    const AARCH64_SYMBOLS: &str = "\
+Section Headers:
+  [Nr] Name              Type            Address          Off    Size   ES Flg Lk Inf Al
+  [ 0]                   NULL            0000000000000000 000000 000000 00      0   0  0
+  [ 1] .text             PROGBITS        ffffffff80000000 010000 008000 00  AX  0   0 4096
+  [ 2] .rodata           PROGBITS        ffffffff80008000 018000 002000 00   A  0   0 4096
+  [ 3] .eh_frame         PROGBITS        ffffffff8000a000 01a000 000400 00   A  0   0  8
+  [ 4] .data             PROGBITS        ffffffff8000b000 01b000 001000 00  WA  0   0 4096
+  [ 5] .bss              NOBITS          ffffffff8000c000 01c000 002000 00  WA  0   0 4096
+  [ 6] .symtab           SYMTAB          0000000000000000 01c000 000100 18      7   3  8
+
+Symbol table '.symtab' contains 3 entries:
+   Num:    Value          Size Type    Bind   Vis      Ndx Name
+     0: 0000000000000000     0 NOTYPE  LOCAL  DEFAULT  UND 
+     1: ffffffff80000100    32 FUNC    GLOBAL DEFAULT    1 nano_core::rust_entry::h0123456789abcdef
+     2: ffffffff8000b010     8 OBJECT  LOCAL  DEFAULT    4 nano_core::COUNTER::h1
+";
+
+    /// Returns (name, virtual address, offset, size) of the sections of a type, by address.
+    fn sections(items: &ParsedCrateItems, ty: SectionType) -> Vec<(&str, usize, usize, usize)> {
+        let mut sections = items.sections.values()
+            .filter(|section| section.ty == ty)
+            .map(|section| (section.name.as_str(), section.virtual_address, section.offset, section.size))
+            .collect::<Vec<_>>();
+        sections.sort_by_key(|section| section.1);
+        sections
+    }
+
+    #[test]
+    fn x86_64() {
+        let items = parse_nanocore_symbol_file(X86_64_SYMBOLS.to_string(), "x86_64").unwrap();
+
+        // .text is mapped from .init's higher-half address
+        let text_start = KERNEL_OFFSET + 0x100000;
+        assert_eq!(
+            sections(&items, SectionType::Text),
+            [ ("nano_core::nano_core_start::h0123456789abcdef", 0xffffffff80101040, 0xffffffff80101040 - text_start, 128) ],
+        );
+        assert_eq!(sections(&items, SectionType::Rodata), [ ("core::fmt::TABLE::h89abcdef01234567", 0xffffffff80109010, 0x10, 100_000) ]);
+        assert_eq!(sections(&items, SectionType::EhFrame), [ ("", 0xffffffff8010b000, 0x2000, 0x800) ]);
+        assert_eq!(sections(&items, SectionType::GccExceptTable), [ ("", 0xffffffff8010b800, 0x2800, 0x100) ]);
+
+        // .tdata symbols keep their TLS offset, and point into the .rodata pages
+        assert_eq!(sections(&items, SectionType::TlsData), [ ("CURRENT_TASK", 8, 0x2908, 8) ]);
+        assert_eq!(sections(&items, SectionType::Data), [ ("logger::LOGGER", 0xffffffff8010c100, 0x100, 16) ]);
+        // .bss is in the .data pages
+        assert_eq!(sections(&items, SectionType::Bss), [ ("memory::FRAMES", 0xffffffff8010d200, 0x1200, 64) ]);
+
+        // symbols of other sections, such as .init; ABS ones are skipped
+        assert_eq!(items.init_symbols.iter().collect::<Vec<_>>(), [ (&"start".to_string(), &0x100000) ]);
+
+        let global = items.global_sections.iter()
+            .map(|shndx| items.sections[shndx].name.as_str())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(global, BTreeSet::from([ "nano_core::nano_core_start::h0123456789abcdef", "CURRENT_TASK", "logger::LOGGER" ]));
+        assert_eq!(items.data_sections.len(), 2);
+        assert_eq!(items.tls_sections.len(), 1);
+    }
+
+    #[test]
+    fn aarch64() {
+        let items = parse_nanocore_symbol_file(AARCH64_SYMBOLS.to_string(), "aarch64").unwrap();
+
+        // .text starts at its own address, and .eh_frame is found although it's PROGBITS
+        assert_eq!(sections(&items, SectionType::Text), [ ("nano_core::rust_entry::h0123456789abcdef", 0xffffffff80000100, 0x100, 32) ]);
+        assert_eq!(sections(&items, SectionType::EhFrame), [ ("", 0xffffffff8000a000, 0x2000, 0x400) ]);
+        assert_eq!(sections(&items, SectionType::Data), [ ("nano_core::COUNTER::h1", 0xffffffff8000b010, 0x10, 8) ]);
+
+        // x86_64's unwinding section type isn't expected there, and aarch64 needs no .init
+        let error = parse_nanocore_symbol_file(AARCH64_SYMBOLS.to_string(), "x86_64").err().unwrap();
+        assert!(error.contains("comes before the .init one"), "{}", error);
+    }
+
+    #[test]
+    fn text_before_init() {
+        let mut lines = X86_64_SYMBOLS.lines().collect::<Vec<_>>();
+        let init = lines.iter().position(|line| line.contains("] .init ")).unwrap();
+        lines.swap(init, init + 1);
+
+        let error = parse_nanocore_symbol_file(lines.join("\n"), "x86_64").err().unwrap();
+        assert_eq!(error, "parse_nanocore_symbol_file(): the .text section header comes before the .init one");
+    }
+
+    #[test]
+    fn unsupported_arch() {
+        assert!(parse_nanocore_symbol_file(X86_64_SYMBOLS.to_string(), "riscv64").is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use crate::log;
+use crate::oops;
+use crate::Config;
+use crate::list_dir;
+use crate::elf;
+use crate::compression;
+
+use std::fs::read;
+use std::fs::write;
+use std::fs::read_to_string;
+use std::collections::BTreeMap;
+
+use rayon::prelude::*;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+struct Sizes {
+    text: u64,
+    rodata: u64,
+    data: u64,
+    bss: u64,
+    /// The size of the file once compressed alone, with `add-bootloader.module-compression`;
+    /// modules are compressed together in the archive, so this isn't their share of it
+    #[serde(alias = "compressed")]
+    compressed_alone: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Module {
+    /// Empty in reports written before modules were keyed by crate
+    #[serde(default)]
+    file: String,
+    #[serde(flatten)]
+    sizes: Sizes,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Report {
+    nano_core: Sizes,
+    /// By crate name, so that modules whose hash changed can be compared
+    modules: BTreeMap<String, Module>,
+}
+
+impl Sizes {
+    /// The size of this object once loaded, excluding `.bss`
+    fn loaded(&self) -> u64 {
+        self.text + self.rodata + self.data
+    }
+
+    fn total<'a, I: Iterator<Item = &'a Sizes>>(sizes: I) -> Sizes {
+        sizes.fold(Sizes::default(), |total, sizes| Sizes {
+            text: total.text + sizes.text,
+            rodata: total.rodata + sizes.rodata,
+            data: total.data + sizes.data,
+            bss: total.bss + sizes.bss,
+            compressed_alone: total.compressed_alone + sizes.compressed_alone,
+        })
+    }
+}
+
+pub fn process(config: &Config) {
+    let stage = "size-report";
+
+    let modules_dir = config.str("directories.modules");
+    let nanocore_path = config.str("nanocore-path");
+    let output = config.str("size-report.output");
+    let baseline_path = config.str("size-report.baseline");
+    let json = config.bool("size-report.json");
+    let codec = config.str("add-bootloader.module-compression");
+
+    // read it first, as it may be the previous report at the same path
+    let baseline = match baseline_path.is_empty() {
+        true => None,
+        _ => Some(read_report(stage, &baseline_path)),
+    };
+
+    log!(stage, "measuring modules");
+
+    let modules = list_dir(stage, &modules_dir)
+        .into_iter()
+        .filter(|(name, is_dir)| !is_dir && name.ends_with(".o"))
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+
+    let modules = modules.par_iter()
+        .map(|name| Module {
+            file: name.clone(),
+            sizes: measure(stage, &codec, &format!("{}/{}", &modules_dir, name)),
+        })
+        .collect::<Vec<_>>();
+
+    let report = Report {
+        nano_core: measure(stage, &codec, &nanocore_path),
+        modules: by_crate(modules),
+    };
+
+    let serialized = serde_json::to_string_pretty(&report).unwrap();
+    write(&output, &serialized).unwrap();
+    log!(stage, "report written to {}", output);
+
+    if json {
+        println!("{}", serialized);
+    } else {
+        print_table(&report, baseline.as_ref());
+    }
+}
+
+fn read_report(stage: &str, path: &str) -> Report {
+    let serialized = match read_to_string(path) {
+        Ok(serialized) => serialized,
+        Err(e) => oops!(stage, "couldn't read {}: {}", path, e),
+    };
+
+    let report: Report = match serde_json::from_str(&serialized) {
+        Ok(report) => report,
+        Err(e) => oops!(stage, "failed to parse {}: {}", path, e),
+    };
+
+    // older reports are keyed by file name
+    let modules = report.modules.into_iter()
+        .map(|(key, module)| match module.file.is_empty() {
+            true => Module { file: key, sizes: module.sizes },
+            false => module,
+        })
+        .collect();
+
+    Report { nano_core: report.nano_core, modules: by_crate(modules) }
+}
+
+/// Keys modules by their crate name: the file name without the `-<hash>.o` suffix.
+///
+/// Several versions of a crate keep their file name, without `.o`, as they can't be told apart.
+fn by_crate(modules: Vec<Module>) -> BTreeMap<String, Module> {
+    let mut counts = BTreeMap::new();
+    for module in &modules {
+        *counts.entry(crate_name(&module.file)).or_insert(0) += 1;
+    }
+
+    modules.into_iter()
+        .map(|module| {
+            let name = crate_name(&module.file);
+            let key = match counts[&name] {
+                1 => name,
+                _ => module.file.strip_suffix(".o").unwrap_or(&module.file).to_string(),
+            };
+            (key, module)
+        })
+        .collect()
+}
+
+fn crate_name(file_name: &str) -> String {
+    let stem = file_name.strip_suffix(".o").unwrap_or(file_name);
+    match stem.rsplit_once('-') {
+        Some((name, hash)) if !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit()) => name.to_string(),
+        _ => stem.to_string(),
+    }
+}
+
+/// Sums the sizes of allocated sections of an object file, by kind.
+fn measure(stage: &str, codec: &str, path: &str) -> Sizes {
+    let bytes = match read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => oops!(stage, "couldn't read {}: {}", path, e),
+    };
+
+    let sections = match elf::sections(&bytes) {
+        Ok(sections) => sections,
+        Err(e) => oops!(stage, "couldn't parse {}: {}", path, e),
+    };
+
+    let mut sizes = Sizes::default();
+
+    for section in sections {
+        if section.flags & elf::SHF_ALLOC == 0 {
+            continue;
+        }
+
+        let size = if section.flags & elf::SHF_EXECINSTR != 0 {
+            &mut sizes.text
+        } else if section.flags & elf::SHF_WRITE == 0 {
+            &mut sizes.rodata
+        } else if section.ty == elf::SHT_NOBITS {
+            &mut sizes.bss
+        } else {
+            &mut sizes.data
+        };
+
+        *size += section.size;
+    }
+
+    sizes.compressed_alone = compression::compress(stage, codec, &bytes).len() as u64;
+
+    sizes
+}
+
+fn print_table(report: &Report, baseline: Option<&Report>) {
+    let zero = Sizes::default();
+
+    let print_header = || {
+        print!("{:50} {:>10} {:>10} {:>10} {:>10} {:>10}", "", "text", "rodata", "data", "bss", "compr.alone");
+        match baseline {
+            Some(_) => println!(" {:>10} {:>10}", "Δ loaded", "Δ c.alone"),
+            None => println!(),
+        }
+    };
+
+    let print_row = |name: &str, sizes: &Sizes, old: Option<&Sizes>| {
+        print!(
+            "{:50} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            name, sizes.text, sizes.rodata, sizes.data, sizes.bss, sizes.compressed_alone,
+        );
+        match baseline.map(|_| old.unwrap_or(&zero)) {
+            Some(old) => println!(
+                " {:>+10} {:>+10}",
+                sizes.loaded() as i64 - old.loaded() as i64,
+                sizes.compressed_alone as i64 - old.compressed_alone as i64,
+            ),
+            None => println!(),
+        }
+    };
+
+    print_header();
+
+    let old_nano_core = baseline.map(|report| &report.nano_core);
+    print_row("nano_core", &report.nano_core, old_nano_core);
+
+    // biggest modules first
+    let mut modules = report.modules.iter().collect::<Vec<_>>();
+    modules.sort_by_key(|(_, module)| std::cmp::Reverse(module.sizes.loaded()));
+
+    for (name, module) in modules {
+        let old = baseline.and_then(|report| report.modules.get(name));
+        print_row(name, &module.sizes, old.map(|old| &old.sizes));
+    }
+
+    let total = Sizes::total(report.modules.values().map(|module| &module.sizes));
+    let old_total = baseline.map(|report| Sizes::total(report.modules.values().map(|module| &module.sizes)));
+
+    println!();
+    print_row("all modules", &total, old_total.as_ref());
+
+    if let Some(baseline) = baseline {
+        let removed = baseline.modules.keys()
+            .filter(|name| !report.modules.contains_key(*name))
+            .collect::<Vec<_>>();
+        for name in removed {
+            println!("{:50} removed", name);
+        }
+
+        let mut grown = report.modules.iter()
+            .filter_map(|(name, module)| {
+                let old = baseline.modules.get(name)?;
+                let growth = module.sizes.loaded() as i64 - old.sizes.loaded() as i64;
+                Some((growth, name)).filter(|(growth, _)| *growth > 0)
+            })
+            .collect::<Vec<_>>();
+        grown.sort_by_key(|(growth, _)| std::cmp::Reverse(*growth));
+
+        println!();
+        println!("{} modules grew:", grown.len());
+        for (growth, name) in grown {
+            println!("• {:48} {:>+10}", name, growth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn module(file: &str, text: u64) -> Module {
+        Module { file: file.to_string(), sizes: Sizes { text, ..Sizes::default() } }
+    }
+
+    #[test]
+    fn crate_names() {
+        assert_eq!(crate_name("k#memory-4c1d9a43e2b7c5f0.o"), "k#memory");
+        assert_eq!(crate_name("a#hello-0123abcd.o"), "a#hello");
+        assert_eq!(crate_name("k#nano_core.serde"), "k#nano_core.serde");
+        assert_eq!(crate_name("a#not-a-hash.o"), "a#not-a-hash");
+        assert_eq!(crate_name("a#libtheseus.o"), "a#libtheseus");
+    }
+
+    #[test]
+    fn keys_survive_hash_changes() {
+        let before = by_crate(vec![ module("k#memory-1111.o", 10), module("k#apic-2222.o", 20) ]);
+        let after = by_crate(vec![ module("k#memory-aaaa.o", 15), module("k#apic-bbbb.o", 20) ]);
+
+        assert_eq!(before.keys().collect::<Vec<_>>(), after.keys().collect::<Vec<_>>());
+        assert_eq!(after["k#memory"].file, "k#memory-aaaa.o");
+        assert_eq!(after["k#memory"].sizes.text - before["k#memory"].sizes.text, 5);
+    }
+
+    #[test]
+    fn versions_of_a_crate_keep_their_file_name() {
+        let modules = by_crate(vec![ module("k#log-1111.o", 1), module("k#log-2222.o", 2), module("k#apic-3333.o", 3) ]);
+        assert_eq!(modules.keys().collect::<Vec<_>>(), [ "k#apic", "k#log-1111", "k#log-2222" ]);
+    }
+
+    #[test]
+    fn old_reports_are_keyed_by_crate() {
+        let dir = TempDir::new("size-report");
+        let path = dir.join("report.json");
+        let old = r#"{
+            "nano_core": { "text": 1, "rodata": 0, "data": 0, "bss": 0, "compressed": 1 },
+            "modules": { "k#memory-1111.o": { "text": 10, "rodata": 0, "data": 0, "bss": 0, "compressed": 7 } }
+        }"#;
+        write(&path, old).unwrap();
+
+        let report = read_report("test", path.to_str().unwrap());
+
+        assert_eq!(report.modules["k#memory"].file, "k#memory-1111.o");
+        assert_eq!(report.modules["k#memory"].sizes.compressed_alone, 7);
+    }
+}
@@ -0,0 +1,112 @@
+//! The nanocore's symbol index: readelf's list of its sections and symbols,
+//! with demangled names. It's written next to the nanocore when linking, and
+//! read by `serialize-nanocore-syms` and `symbolize`.
+
+use crate::log;
+use crate::oops;
+use crate::Config;
+use crate::check_result;
+
+use std::process::Command;
+use std::fs::metadata;
+use std::fs::read_to_string;
+use std::fs::write as write_file;
+
+use rustc_demangle::demangle;
+
+/// Writes the index of the nanocore's symbols to `link-nanocore.symbol-index`.
+pub fn write(stage: &str, config: &Config) {
+    let index_path = config.str("link-nanocore.symbol-index");
+    let readelf = config.str("link-nanocore.readelf");
+    let nanocore_bin = config.str("nanocore-path");
+
+    if config.as_ref().get("serialize-nanocore-syms").and_then(|table| table.get("readelf")).is_some() {
+        log!(stage, "warning: serialize-nanocore-syms.readelf is ignored; set link-nanocore.readelf instead");
+    }
+
+    log!(stage, "indexing the symbols of {}", nanocore_bin);
+
+    let result = Command::new(readelf)
+        .arg("-W")
+        .arg("-S")
+        .arg("-s")
+        .arg(&nanocore_bin)
+        .output();
+
+    let readelf_output = match result {
+        Ok(output) => {
+            check_result(stage, Ok(output.status), "readelf");
+            match String::from_utf8(output.stdout) {
+                Ok(output) => output,
+                Err(e) => oops!(stage, "readelf's output isn't valid UTF-8: {}", e),
+            }
+        },
+        _ => oops!(stage, "readelf"),
+    };
+
+    let mut readelf_lines = readelf_output.lines();
+    let mut filtered = String::new();
+
+    while let Some(line) = readelf_lines.next() {
+        // copy lines into filtered, until we find the "Symbol table" line
+        if !line.starts_with("Symbol table") {
+            filtered.push_str(line);
+            filtered.push('\n');
+            continue;
+        } else {
+            // we found the "Symbol table" line
+            filtered.push_str(line); // push that "Symbol table" line
+            filtered.push('\n');
+            match readelf_lines.next() {
+                // and the next one ("   Num:    Value ...")
+                Some(header) => filtered.push_str(header),
+                None => oops!(stage, "readelf's symbol table has no header"),
+            }
+            filtered.push('\n');
+            break; // we're at the first symbol table entry, continue onto the next part
+        }
+    }
+
+    // parse each symbol table entry and demangle the names
+    for line in readelf_lines {
+        // we need to find the mangled symbol in each symtab entry, which always starts with "_ZN"
+        if let Some(index) = line.find("_ZN") {
+            let (first_half, name_mangled) = line.split_at(index);
+            let demangled = demangle(name_mangled).to_string();
+            filtered.push_str(first_half); // no newline after this, since it's just a split line
+            filtered.push_str(&demangled);
+            filtered.push('\n');
+        }
+        // if we cannot find "_ZN", then there wasn't a mangled symbol (it might've been no_mangle)
+        else {
+            // so just preserve the line as is
+            filtered.push_str(line);
+            filtered.push('\n');
+        }
+    }
+
+    if let Err(e) = write_file(&index_path, &filtered) {
+        oops!(stage, "couldn't write {}: {}", index_path, e);
+    }
+}
+
+/// Reads the symbol index, writing it first if it's missing or older than the nanocore.
+pub fn load(stage: &str, config: &Config) -> String {
+    let index_path = config.str("link-nanocore.symbol-index");
+    let nanocore_bin = config.str("nanocore-path");
+
+    let modified = |path: &str| metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let up_to_date = match (modified(&index_path), modified(&nanocore_bin)) {
+        (Some(index), Some(nanocore)) => index >= nanocore,
+        _ => false,
+    };
+
+    if !up_to_date {
+        write(stage, config);
+    }
+
+    match read_to_string(&index_path) {
+        Ok(index) => index,
+        Err(e) => oops!(stage, "couldn't read {}: {}", index_path, e),
+    }
+}
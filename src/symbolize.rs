@@ -0,0 +1,233 @@
+use crate::log;
+use crate::oops;
+use crate::Config;
+use crate::symbol_index;
+
+use std::io::stdin;
+use std::io::BufRead;
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    section: String,
+    name: String,
+}
+
+/// Resolves nanocore addresses to symbols, using the symbol index.
+///
+/// Addresses are taken from `symbolize.addresses`; if there are none, each line
+/// of the standard input (e.g. a backtrace) is printed with its addresses resolved.
+pub fn process(config: &Config) {
+    let stage = "symbolize";
+
+    let addresses = config.vec("symbolize.addresses");
+
+    let symbols = parse_index(&symbol_index::load(stage, config));
+    log!(stage, "{} symbols in the index", symbols.len());
+
+    if !addresses.is_empty() {
+        for address in addresses {
+            let value = match parse_address(&address) {
+                Some(value) => value,
+                None => oops!(stage, "invalid address {:?}; must be hexadecimal", address),
+            };
+            match find(&symbols, value) {
+                Some(symbol) => println!("{} => {}", address, describe(symbol, value)),
+                None => println!("{} => ?", address),
+            }
+        }
+        return;
+    }
+
+    // backtraces may contain anything, so lines which aren't valid UTF-8 are decoded lossily
+    for line in stdin().lock().split(b'\n') {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => oops!(stage, "couldn't read the standard input: {}", e),
+        };
+        let line = String::from_utf8_lossy(&line);
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        let resolved = line.split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| word.starts_with("0x"))
+            .filter_map(|word| {
+                let address = parse_address(word)?;
+                let symbol = find(&symbols, address)?;
+                Some(format!("{} => {}", word, describe(symbol, address)))
+            })
+            .collect::<Vec<_>>();
+
+        match resolved.is_empty() {
+            true => println!("{}", line),
+            false => println!("{}    [{}]", line, resolved.join(", ")),
+        }
+    }
+}
+
+fn parse_address(string: &str) -> Option<u64> {
+    let digits = string.strip_prefix("0x").unwrap_or(string);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+/// Finds the closest symbol which contains `address`.
+fn find(symbols: &[Symbol], address: u64) -> Option<&Symbol> {
+    let count = symbols.partition_point(|symbol| symbol.address <= address);
+    symbols[..count].iter()
+        .rev()
+        .find(|symbol| address < symbol.address + symbol.size.max(1))
+}
+
+fn describe(symbol: &Symbol, address: u64) -> String {
+    match address - symbol.address {
+        0 => format!("{} ({})", symbol.name, symbol.section),
+        offset => format!("{}+{:#x} ({})", symbol.name, offset, symbol.section),
+    }
+}
+
+/// Splits the first `count` whitespace-separated columns of `line`, and returns the rest.
+fn columns(line: &str, count: usize) -> Option<(Vec<&str>, &str)> {
+    let mut rest = line.trim_start();
+    let mut columns = Vec::with_capacity(count);
+    for _ in 0..count {
+        let end = rest.find(char::is_whitespace)?;
+        columns.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    Some((columns, rest))
+}
+
+/// Reads the named symbols of the index, with their section, sorted by address.
+fn parse_index(index: &str) -> Vec<Symbol> {
+    let mut lines = index.lines();
+    let mut sections = Vec::new();
+
+    // section headers, e.g. "  [ 2] .text   PROGBITS   ffffffff80101000 ..."
+    for line in lines.by_ref() {
+        if line.contains(".symtab") && !line.contains("SYMTAB") {
+            break;
+        }
+
+        let (open, close) = match (line.find('['), line.find(']')) {
+            (Some(open), Some(close)) if open < close => (open, close),
+            _ => continue,
+        };
+        if let Ok(index) = line[open + 1..close].trim().parse::<usize>() {
+            let name = line[close + 1..].split_whitespace().next().unwrap_or("");
+            sections.push((index, name.to_string()));
+        }
+    }
+
+    let mut symbols = lines
+        // Num: Value Size Type Bind Vis Ndx Name
+        .filter_map(|line| columns(line, 7))
+        .filter_map(|(columns, name)| {
+            let address = u64::from_str_radix(columns[1], 16).ok()?;
+            let size = match columns[2].strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                None => columns[2].parse().ok()?,
+            };
+            let index = columns[6].parse::<usize>().ok()?;
+            let section = sections.iter().find(|(i, _)| *i == index)?.1.clone();
+
+            match columns[3] {
+                "FUNC" | "OBJECT" | "NOTYPE" if !name.is_empty() => Some(Symbol { address, size, section, name: name.to_string() }),
+                _ => None,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    symbols.sort_by_key(|symbol| symbol.address);
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `readelf -W -S -s` of a nanocore, as in the symbol index, abridged.
+    const INDEX: &str = "\
+There are 6 section headers, starting at offset 0x2d8a70:
+
+Section Headers:
+  [Nr] Name              Type            Address          Off    Size   ES Flg Lk Inf Al
+  [ 0]                   NULL            0000000000000000 000000 000000 00      0   0  0
+  [ 1] .init             PROGBITS        0000000000100000 001000 000e46 00  AX  0   0 4096
+  [ 2] .text             PROGBITS        ffffffff80101000 002000 0d2ab0 00  AX  0   0 4096
+  [ 3] .rodata           PROGBITS        ffffffff801d4000 0d5000 03d2f8 00   A  0   0 4096
+  [ 4] .symtab           SYMTAB          0000000000000000 2c7e40 00a5c8 18      5 101  8
+  [ 5] .strtab           STRTAB          0000000000000000 2d2408 006591 00      0   0  1
+Key to Flags:
+  W (write), A (alloc), X (execute), M (merge), S (strings), I (info),
+  L (link order), O (extra OS processing required), G (group), T (TLS),
+  C (compressed), x (unknown), o (OS specific), E (exclude), D (ordered), l (large),
+  p (processor specific)
+
+Symbol table '.symtab' contains 9 entries:
+   Num:    Value          Size Type    Bind   Vis      Ndx Name
+     0: 0000000000000000     0 NOTYPE  LOCAL  DEFAULT  UND 
+     1: 0000000000000000     0 FILE    LOCAL  DEFAULT  ABS nano_core.1a2b3c-cgu.0
+     2: 0000000000100000     0 NOTYPE  GLOBAL DEFAULT    1 start
+     3: ffffffff80101000    64 FUNC    LOCAL  DEFAULT    2 core::ptr::drop_in_place<alloc::string::String>
+     4: ffffffff80101040   256 FUNC    GLOBAL DEFAULT    2 nano_core::nano_core_start
+     5: ffffffff80101080    16 FUNC    LOCAL  DEFAULT    2 nano_core::nano_core_start::{{closure}}
+     6: ffffffff801d4000 0x186a0 OBJECT LOCAL  DEFAULT    3 unicode_data::TABLE
+     7: ffffffff80101000     0 SECTION LOCAL  DEFAULT    2 
+     8: 0000000000000200     0 NOTYPE  LOCAL  DEFAULT  ABS KERNEL_STACK_PAGES
+";
+
+    fn names(symbols: &[Symbol]) -> Vec<(&str, &str)> {
+        symbols.iter().map(|symbol| (symbol.name.as_str(), symbol.section.as_str())).collect()
+    }
+
+    #[test]
+    fn columns_split_on_any_whitespace() {
+        assert_eq!(
+            columns("     4: ffffffff80101040   256 FUNC    GLOBAL DEFAULT    2 a b", 7),
+            Some((vec![ "4:", "ffffffff80101040", "256", "FUNC", "GLOBAL", "DEFAULT", "2" ], "a b")),
+        );
+        // the name may be missing, but not other columns
+        assert_eq!(columns("     0: 0000000000000000     0 NOTYPE  LOCAL  DEFAULT  UND ", 7).unwrap().1, "");
+        assert_eq!(columns("   Num:    Value          Size Type", 7), None);
+    }
+
+    #[test]
+    fn index_is_parsed() {
+        let symbols = parse_index(INDEX);
+
+        // named functions, objects and labels with a section, by address
+        assert_eq!(names(&symbols), [
+            ("start", ".init"),
+            ("core::ptr::drop_in_place<alloc::string::String>", ".text"),
+            ("nano_core::nano_core_start", ".text"),
+            ("nano_core::nano_core_start::{{closure}}", ".text"),
+            ("unicode_data::TABLE", ".rodata"),
+        ]);
+        // decimal and hexadecimal sizes
+        assert_eq!(symbols[2].size, 256);
+        assert_eq!(symbols[4].size, 100_000);
+        assert_eq!(symbols[4].address, 0xffffffff801d4000);
+    }
+
+    #[test]
+    fn symbols_are_found_up_to_their_last_byte() {
+        let symbols = parse_index(INDEX);
+        let find_name = |address| find(&symbols, address).map(|symbol| symbol.name.as_str());
+
+        assert_eq!(find_name(0xffffffff80100fff), None);
+        assert_eq!(find_name(0xffffffff80101000), Some("core::ptr::drop_in_place<alloc::string::String>"));
+        assert_eq!(find_name(0xffffffff8010103f), Some("core::ptr::drop_in_place<alloc::string::String>"));
+        assert_eq!(find_name(0xffffffff80101040), Some("nano_core::nano_core_start"));
+        // the closest symbol wins, then the enclosing one takes over again
+        assert_eq!(find_name(0xffffffff8010108f), Some("nano_core::nano_core_start::{{closure}}"));
+        assert_eq!(find_name(0xffffffff80101090), Some("nano_core::nano_core_start"));
+        assert_eq!(find_name(0xffffffff8010113f), Some("nano_core::nano_core_start"));
+        assert_eq!(find_name(0xffffffff80101140), None);
+
+        // symbols without a size only match their own address
+        assert_eq!(find_name(0x100000), Some("start"));
+        assert_eq!(find_name(0x100001), None);
+
+        assert_eq!(find_name(0xffffffff801d4000 + 99_999), Some("unicode_data::TABLE"));
+        assert_eq!(find_name(0xffffffff801d4000 + 100_000), None);
+        assert_eq!(describe(find(&symbols, 0xffffffff80101048).unwrap(), 0xffffffff80101048), "nano_core::nano_core_start+0x8 (.text)");
+    }
+}
@@ -0,0 +1,176 @@
+//! Minimal template engine for bootloader configuration files.
+//!
+//! - `{key}` is replaced by a variable of the current scope or of an enclosing one,
+//!   falling back to the config value of the same name, e.g. `{arch}`.
+//! - `{#list}...{/list}` repeats its content once per item of `list`,
+//!   each item being the scope of its variables.
+//!
+//! Braces which don't form a tag, like grub's, are kept as-is.
+
+use crate::oops;
+use crate::opt;
+use crate::Config;
+use crate::DEFAULT_CONFIG;
+
+use std::collections::HashMap;
+
+use toml::Value;
+
+#[derive(Default)]
+pub struct Scope {
+    vars: HashMap<String, String>,
+    lists: HashMap<String, Vec<Scope>>,
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn var<V: ToString>(mut self, key: &str, value: V) -> Self {
+        self.vars.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn list(mut self, key: &str, items: Vec<Scope>) -> Self {
+        self.lists.insert(key.to_string(), items);
+        self
+    }
+}
+
+pub fn render(stage: &str, template: &str, scope: &Scope, config: &Config) -> String {
+    let mut output = String::new();
+    render_into(stage, template, &mut vec![ scope ], config, &mut output);
+    output
+}
+
+fn render_into(
+    stage: &str,
+    template: &str,
+    stack: &mut Vec<&Scope>,
+    config: &Config,
+    output: &mut String,
+) {
+    let mut rest = template;
+
+    while let Some(i) = rest.find('{') {
+        output.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        let (kind, name, tag_len) = match parse_tag(rest) {
+            Some(tag) => tag,
+            None => {
+                output.push('{');
+                rest = &rest[1..];
+                continue;
+            },
+        };
+        rest = &rest[tag_len..];
+
+        match kind {
+            Some('#') => {
+                let (body, after) = match split_section(rest, name) {
+                    Some(split) => split,
+                    None => oops!(stage, "template section {{#{}}} is never closed", name),
+                };
+
+                let items = match stack.iter().rev().find_map(|scope| scope.lists.get(name)) {
+                    Some(items) => items,
+                    None => oops!(stage, "unknown template list {{#{}}}", name),
+                };
+
+                // a tag alone on its line doesn't leave an empty line behind
+                let body = body.strip_prefix('\n').unwrap_or(body);
+                for item in items {
+                    stack.push(item);
+                    render_into(stage, body, stack, config, output);
+                    stack.pop();
+                }
+
+                rest = after.strip_prefix('\n').unwrap_or(after);
+            },
+            Some(_) => oops!(stage, "template section {{/{}}} was never opened", name),
+            None => {
+                let value = stack.iter().rev()
+                    .find_map(|scope| scope.vars.get(name).cloned())
+                    .or_else(|| config_value(config, name));
+
+                match value {
+                    Some(value) => output.push_str(&value),
+                    None => oops!(stage, "unknown template variable {{{}}}", name),
+                }
+            },
+        }
+    }
+
+    output.push_str(rest);
+}
+
+/// Parses `{name}`, `{#name}` or `{/name}` at the start of `string`.
+///
+/// Returns the kind of tag, the name and the length of the tag.
+fn parse_tag(string: &str) -> Option<(Option<char>, &str, usize)> {
+    let end = string.find('}')?;
+    let inner = &string[1..end];
+
+    let (kind, name) = match inner.strip_prefix(['#', '/']) {
+        Some(name) => (inner.chars().next(), name),
+        None => (None, inner),
+    };
+
+    let valid = |c: char| c.is_ascii_alphanumeric() || "-_.:".contains(c);
+    match !name.is_empty() && name.chars().all(valid) {
+        true => Some((kind, name, end + 1)),
+        false => None,
+    }
+}
+
+/// Splits `string` at the `{/name}` tag closing a section, skipping nested sections of the same name.
+fn split_section<'a>(string: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let open = format!("{{#{}}}", name);
+    let close = format!("{{/{}}}", name);
+
+    let mut depth = 0;
+    let mut offset = 0;
+    loop {
+        let rest = &string[offset..];
+        let next_close = rest.find(&close)?;
+        match rest.find(&open) {
+            Some(next_open) if next_open < next_close => {
+                depth += 1;
+                offset += next_open + open.len();
+            },
+            _ if depth > 0 => {
+                depth -= 1;
+                offset += next_close + close.len();
+            },
+            _ => {
+                let end = offset + next_close;
+                return Some((&string[..end], &string[end + close.len()..]));
+            },
+        }
+    }
+}
+
+/// Converts a config value to a string, if the key exists.
+fn config_value(config: &Config, key: &str) -> Option<String> {
+    let defaults = DEFAULT_CONFIG.parse::<Value>().unwrap();
+    let exists = |mut value: &Value| key.split('.').all(|part| match value.get(part) {
+        Some(inner) => {
+            value = inner;
+            true
+        },
+        None => false,
+    });
+
+    if !exists(config.as_ref()) && !exists(&defaults) {
+        return None;
+    }
+
+    match opt(config.as_ref(), key) {
+        Value::String(_) => Some(config.str(key)),
+        Value::Array(_) => Some(config.vec(key).join(" ")),
+        Value::Table(_) => None,
+        value => Some(value.to_string()),
+    }
+}
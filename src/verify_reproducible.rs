@@ -0,0 +1,135 @@
+use crate::log;
+use crate::oops;
+use crate::Config;
+use crate::STAGES;
+use crate::parse_stage;
+use crate::list_dir;
+use crate::add_bootloader;
+use crate::compression;
+
+use std::fs::create_dir_all;
+use std::fs::read;
+use std::fs::remove_dir_all;
+use std::path::Path;
+
+use toml::Value;
+
+/// Runs every stage from `directories` to `add-bootloader` twice, each time
+/// from scratch in one of `verify-reproducible.build-dirs`, and compares the
+/// image, the module archive, the nanocore and each module.
+///
+/// The second build has newer modification times and a different build
+/// directory, so the outputs must depend on neither.
+pub fn process(config: &Config) {
+    let stage = "verify-reproducible";
+
+    let build_dirs = config.vec("verify-reproducible.build-dirs");
+    if build_dirs.len() != 2 || build_dirs[0] == build_dirs[1] {
+        oops!(stage, "verify-reproducible.build-dirs must be two different directories");
+    }
+
+    // limine is an input of both builds, it doesn't need to be fetched or built twice
+    let shared = [ "add-bootloader.extract-dir", "add-bootloader.limine-deploy-cache" ];
+
+    let builds = build_dirs.iter().enumerate().map(|(index, build_dir)| {
+        let mut build = config.with("build-dir", Value::String(build_dir.clone()));
+        for key in shared {
+            build = build.with(key, Value::String(config.str(key)));
+        }
+
+        if Path::new(build_dir).exists() {
+            if let Err(e) = remove_dir_all(build_dir) {
+                oops!(stage, "couldn't remove {}: {}", build_dir, e);
+            }
+        }
+        // the directories stage only creates the last level of build-dir
+        if let Err(e) = create_dir_all(build_dir) {
+            oops!(stage, "couldn't create {}: {}", build_dir, e);
+        }
+
+        log!(stage, "build {} of 2, in {}", index + 1, build_dir);
+        let first = parse_stage("directories", false);
+        let last = parse_stage("add-bootloader", true);
+        for processor in &STAGES[first..=last] {
+            processor(&build);
+        }
+
+        build
+    }).collect::<Vec<_>>();
+
+    let outputs = builds.iter().map(|build| outputs(stage, build)).collect::<Vec<_>>();
+    let (first, second) = (&outputs[0], &outputs[1]);
+
+    let mut differences = 0;
+    for (name, first_path) in first {
+        let second_path = match second.iter().find(|(other, _)| other == name) {
+            Some((_, path)) => path,
+            None => {
+                println!("{}: only in the first build", name);
+                differences += 1;
+                continue;
+            },
+        };
+
+        let (first, second) = (read_output(stage, first_path), read_output(stage, second_path));
+
+        let offset = first.iter().zip(&second).position(|(a, b)| a != b);
+        let offset = match (offset, first.len() == second.len()) {
+            (Some(offset), _) => offset,
+            (None, false) => first.len().min(second.len()),
+            (None, true) => continue,
+        };
+
+        println!(
+            "{}: builds differ at offset {:#x} (sizes: {} and {} bytes)",
+            name, offset, first.len(), second.len(),
+        );
+        differences += 1;
+    }
+    for (name, _) in second.iter().filter(|(name, _)| !first.iter().any(|(other, _)| other == name)) {
+        println!("{}: only in the second build", name);
+        differences += 1;
+    }
+
+    match differences {
+        0 => log!(stage, "both builds are identical"),
+        _ => oops!(stage, "{} outputs are not reproducible; both builds are kept in {}", differences, build_dirs.join(" and ")),
+    }
+}
+
+/// Lists the outputs of a build, as a name shared by both builds and the path of the file.
+fn outputs(stage: &str, config: &Config) -> Vec<(String, String)> {
+    let image = add_bootloader::output_path(stage, config);
+    let isofiles_dir = config.str("directories.isofiles");
+    let modules_dir = config.str("directories.modules");
+    let bootloader = config.str("add-bootloader.bootloader");
+
+    // the EFI system partition directory only holds copies, except for limine.cfg
+    let mut outputs = match bootloader.as_str() {
+        "uefi-dir" => vec![ ("limine.cfg".to_string(), format!("{}/limine.cfg", image)) ],
+        _ => vec![ ("image".to_string(), image) ],
+    };
+    if bootloader == "limine" || bootloader == "uefi-dir" {
+        let codec = config.str("add-bootloader.module-compression");
+        let archive = format!("modules.cpio{}", compression::extension(stage, &codec));
+        outputs.push((archive.clone(), format!("{}/{}", isofiles_dir, archive)));
+    }
+    outputs.push(("nano_core".to_string(), config.str("nanocore-path")));
+
+    // modules point at the crates which differ
+    let mut modules = list_dir(stage, &modules_dir).into_iter()
+        .filter(|(_, is_dir)| !is_dir)
+        .map(|(name, _)| (format!("modules/{}", name), format!("{}/{}", modules_dir, name)))
+        .collect::<Vec<_>>();
+    modules.sort();
+    outputs.extend(modules);
+
+    outputs
+}
+
+fn read_output(stage: &str, path: &str) -> Vec<u8> {
+    match read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => oops!(stage, "couldn't read {}: {}", path, e),
+    }
+}
@@ -0,0 +1,157 @@
+use crate::log;
+use crate::oops;
+use crate::Config;
+use crate::list_dir;
+
+use std::process::Command;
+use std::fs::read;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+
+use bincode::serde::decode_from_slice;
+use bincode::config::standard;
+use mod_mgmt::serde::SerializedCrate;
+use rustc_demangle::demangle;
+use rayon::prelude::*;
+
+/// The global symbols a module defines and the ones it needs.
+struct ModuleSymbols {
+    name: String,
+    defined: Vec<String>,
+    /// Weak definitions may legitimately appear in several modules
+    weak: Vec<String>,
+    undefined: Vec<String>,
+}
+
+pub fn process(config: &Config) {
+    let stage = "verify-symbols";
+
+    let modules_dir = config.str("directories.modules");
+    let nanocore_syms = config.str("serialize-nanocore-syms.output-path");
+
+    let nm = config.str("verify-symbols.nm");
+    let deny = config.bool("verify-symbols.deny");
+
+    log!(stage, "reading nano_core symbols");
+
+    let nanocore_defined = read_nanocore_symbols(stage, &nanocore_syms);
+
+    log!(stage, "reading module symbols");
+
+    let modules = list_dir(stage, &modules_dir)
+        .into_iter()
+        .filter(|(name, is_dir)| !is_dir && name.ends_with(".o"))
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+
+    let modules = modules.par_iter()
+        .map(|name| read_module_symbols(stage, &nm, &modules_dir, name))
+        .collect::<Vec<_>>();
+
+    // symbol => modules defining it
+    let mut definitions = BTreeMap::<&str, Vec<&str>>::new();
+    let mut weak_definitions = HashSet::new();
+    for module in &modules {
+        for symbol in &module.defined {
+            definitions.entry(symbol).or_default().push(&module.name);
+        }
+        weak_definitions.extend(module.weak.iter().map(String::as_str));
+    }
+
+    let mut problems = 0;
+
+    for module in &modules {
+        let missing = module.undefined.iter()
+            .filter(|symbol| {
+                !definitions.contains_key(symbol.as_str())
+                    && !weak_definitions.contains(symbol.as_str())
+                    && !nanocore_defined.contains(symbol.as_str())
+            })
+            .collect::<BTreeSet<_>>();
+
+        for symbol in missing {
+            println!("{}: undefined symbol {}", module.name, symbol);
+            problems += 1;
+        }
+    }
+
+    // Kernel crates linked into the nano_core are usually also present as modules,
+    // so only definitions in several modules are reported.
+    for (symbol, modules) in &definitions {
+        if modules.len() > 1 {
+            println!("{} is defined in several modules: {}", symbol, modules.join(", "));
+            problems += 1;
+        }
+    }
+
+    match (problems, deny) {
+        (0, _) => log!(stage, "all symbols of {} modules are resolved", modules.len()),
+        (_, true) => oops!(stage, "found {} symbol problems", problems),
+        (_, false) => log!(stage, "warning: found {} symbol problems", problems),
+    }
+}
+
+/// Lists the names of the global symbols which the nano_core makes available to modules.
+fn read_nanocore_symbols(stage: &str, path: &str) -> HashSet<String> {
+    let bytes = match read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => oops!(stage, "couldn't read {}: {}", path, e),
+    };
+
+    let serialized_crate: SerializedCrate = match decode_from_slice(&bytes, standard()) {
+        Ok((serialized_crate, _)) => serialized_crate,
+        Err(e) => oops!(stage, "couldn't deserialize {}: {}", path, e),
+    };
+
+    let sections = serialized_crate.sections
+        .into_iter()
+        .filter(|(_, section)| section.global)
+        .map(|(_, section)| section.name);
+    let init_symbols = serialized_crate.init_symbols.into_keys();
+
+    sections.chain(init_symbols).collect()
+}
+
+/// Lists the global symbols of a module using `nm`.
+///
+/// Names are demangled like `serialize-nanocore-syms` does,
+/// so that they can be compared with the nano_core's.
+fn read_module_symbols(stage: &str, nm: &str, modules_dir: &str, name: &str) -> ModuleSymbols {
+    let path = format!("{}/{}", modules_dir, name);
+
+    let output = match Command::new(nm).arg("-P").arg(&path).output() {
+        Ok(output) if output.status.success() => output.stdout,
+        _ => oops!(stage, "{} invocation failed on {}", nm, name),
+    };
+
+    let mut module = ModuleSymbols {
+        name: name.to_string(),
+        defined: Vec::new(),
+        weak: Vec::new(),
+        undefined: Vec::new(),
+    };
+
+    // POSIX format: "name type [value size]"
+    for line in String::from_utf8_lossy(&output).lines() {
+        let mut parts = line.split_whitespace();
+        let (symbol, kind) = match (parts.next(), parts.next()) {
+            (Some(symbol), Some(kind)) => (symbol, kind),
+            _ => continue,
+        };
+
+        let symbol = demangle(symbol).to_string();
+
+        match kind {
+            "U" => module.undefined.push(symbol),
+            // weak symbols and unique globals
+            "W" | "V" | "u" => module.weak.push(symbol),
+            // other uppercase kinds are global definitions
+            _ if kind.chars().all(|c| c.is_ascii_uppercase()) => module.defined.push(symbol),
+            // local symbols and weak undefined symbols ("w", "v")
+            _ => (),
+        }
+    }
+
+    module
+}